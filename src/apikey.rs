@@ -1,40 +1,466 @@
+/*! API keys, each with a name, the scopes it may use, an optional expiry and
+its own limits. They are configured under `keys` in `App.toml`:
+
+```toml
+[default.keys.marketing]
+key = "sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+scopes = ["render", "templates"]
+expires = "2025-01-01T00:00:00Z"
+limits = { max_width = 2048, max_height = 2048 }
+```
+
+A `key` is either the secret itself or, so config can be shared without
+sharing secrets, `sha256:` and the hex SHA-256 of it, as printed by
+`social-image keygen`. Keys are random and long, so a fast hash is as good as
+a slow one. Every presented key is hashed and compared against all the hashes
+in constant time.
+
+To rotate a key, give it a list with both the new and the old, optionally
+with when the old one stops working:
+
+```toml
+key = ["sha256:<new>", { key = "sha256:<old>", expires = "2024-07-01T00:00:00Z" }]
+```
+
+The older single `key` setting is still accepted, as a key named `key` with
+every scope. Without it only the named keys are.
+
+A key is sent as the `x-api-key` header, or as `Authorization: Bearer <key>`.
+Without one a route answers `401`; with a key that is unknown, expired or
+lacks the route's scope, `403`; and with one over its rate, `429`.
+*/
+use crate::instrumentation::TracingSpan;
+use crate::ratelimit::RateLimiter;
+use crate::types::{ApiError, Limits};
 use crate::AppConfig;
+
+use eyre::eyre;
+use rand::Rng;
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::json;
+use rocket::serde::{de, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::marker::PhantomData;
+use subtle::ConstantTimeEq;
+use time::OffsetDateTime;
+use tracing::Span;
+
+const SHA256_PREFIX: &str = "sha256:";
+
+/// What a key may be used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Scope {
+    /// Render images, list fonts and sign render urls
+    Render,
+    /// Upload, update and delete stored templates
+    Templates,
+    /// Everything, including seeing the configured keys
+    Admin,
+}
+
+impl Scope {
+    fn name(self) -> &'static str {
+        match self {
+            Scope::Render => "render",
+            Scope::Templates => "templates",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+/// One key's configuration
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyConfig {
+    /// The secrets sent in the `x-api-key` header, several while rotating.
+    /// Never serialized, so they can't be shown.
+    #[serde(skip_serializing, deserialize_with = "Secret::deserialize_all")]
+    pub key: Vec<Secret>,
+
+    #[serde(default = "KeyConfig::default_scopes")]
+    pub scopes: Vec<Scope>,
+
+    /// When the key stops working
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires: Option<OffsetDateTime>,
+
+    /// Replaces the server's limits for this key's requests
+    #[serde(default)]
+    pub limits: KeyLimits,
+}
+
+impl KeyConfig {
+    fn default_scopes() -> Vec<Scope> {
+        vec![Scope::Render]
+    }
+
+    fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    fn expired(&self) -> bool {
+        is_past(self.expires)
+    }
+}
+
+fn is_past(time: Option<OffsetDateTime>) -> bool {
+    time.is_some_and(|time| time <= OffsetDateTime::now_utc())
+}
+
+/// One accepted secret, kept only as its SHA-256 digest
+#[derive(Clone, Debug)]
+pub struct Secret {
+    digest: [u8; 32],
+    /// When this secret stops working, for the old one during a rotation
+    expires: Option<OffsetDateTime>,
+}
+
+/// How a secret is written in the config
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum SecretConfig {
+    Key(String),
+    Rotating {
+        key: String,
+        #[serde(default, with = "time::serde::rfc3339::option")]
+        expires: Option<OffsetDateTime>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum SecretsConfig {
+    One(SecretConfig),
+    Many(Vec<SecretConfig>),
+}
+
+impl Secret {
+    /// A secret from the config: `sha256:` and the hex digest, or the secret itself
+    pub fn parse(key: &str, expires: Option<OffsetDateTime>) -> eyre::Result<Self> {
+        let digest = match key.strip_prefix(SHA256_PREFIX) {
+            Some(hex) => {
+                from_hex(hex).ok_or(eyre!("{SHA256_PREFIX} must be followed by 64 hex digits"))?
+            }
+            None => Sha256::digest(key).into(),
+        };
+        Ok(Secret { digest, expires })
+    }
+
+    fn deserialize_all<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Self>, D::Error> {
+        let secrets = match SecretsConfig::deserialize(deserializer)? {
+            SecretsConfig::One(secret) => vec![secret],
+            SecretsConfig::Many(secrets) => secrets,
+        };
+        secrets
+            .into_iter()
+            .map(|secret| match secret {
+                SecretConfig::Key(key) => Secret::parse(&key, None),
+                SecretConfig::Rotating { key, expires } => Secret::parse(&key, expires),
+            })
+            .collect::<eyre::Result<_>>()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Whether a config `key` is a hash rather than the secret itself
+pub fn is_hash(key: &str) -> bool {
+    key.starts_with(SHA256_PREFIX)
+}
+
+/// A new random key, and the hash of it to put in the config
+pub fn generate() -> (String, String) {
+    let key = bs58::encode(rand::thread_rng().gen::<[u8; 32]>()).into_string();
+    let hash = format!("{SHA256_PREFIX}{}", to_hex(&Sha256::digest(&key)));
+    (key, hash)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(hex, "{b:02x}");
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// A key's own upload and render limits. Unset ones are the server's.
+/// `max_request_bytes` is enforced while reading the body, before the key is
+/// known, so it can't be set per key. The rates and quotas are the key's own
+/// too, replacing the server's `rate_per_sec`, `rate_burst`, `daily_quota` and
+/// `monthly_quota`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyLimits {
+    pub max_resource_bytes: Option<u64>,
+    pub max_resources: Option<usize>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_pixels: Option<u64>,
+    pub max_svg_nodes: Option<u32>,
+    pub rate_per_sec: Option<f64>,
+    pub rate_burst: Option<u32>,
+    pub daily_quota: Option<u64>,
+    pub monthly_quota: Option<u64>,
+}
+
+/// Every accepted key, by name
+pub struct Keys(Vec<(String, KeyConfig)>);
+
+impl Keys {
+    /// The named keys, plus the single `key` setting when it is set.
+    pub fn from_config(config: &AppConfig) -> eyre::Result<Self> {
+        let mut keys: Vec<_> = config
+            .keys
+            .iter()
+            .map(|(name, key)| (name.clone(), key.clone()))
+            .collect();
+        keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+        if let Some(key) = config.key.as_deref().filter(|key| !key.is_empty()) {
+            let key = KeyConfig {
+                key: vec![Secret::parse(key, None)?],
+                scopes: vec![Scope::Admin],
+                expires: None,
+                limits: KeyLimits::default(),
+            };
+            keys.push(("key".to_string(), key));
+        }
+        Ok(Keys(keys))
+    }
+
+    /// The key `key` is a secret of, and whether that secret has expired.
+    /// Every secret is compared, in constant time, so how long this takes
+    /// doesn't tell which key or how much of it matched.
+    fn find(&self, key: &str) -> Option<(&str, &KeyConfig, bool)> {
+        let digest: [u8; 32] = Sha256::digest(key).into();
+        let mut found = None;
+        for (name, config) in &self.0 {
+            for secret in &config.key {
+                if bool::from(secret.digest.ct_eq(&digest)) {
+                    found = Some((name.as_str(), config, is_past(secret.expires)));
+                }
+            }
+        }
+        found
+    }
+
+    /// The key named `name`, while it hasn't expired and allows `S`.
+    /// For a signed url, which is held to the key that signed it.
+    pub fn named<S: RequiredScope>(&self, name: &str) -> Option<ApiKey<'_, S>> {
+        let (name, config) = self.0.iter().find(|(key, _)| key == name)?;
+        (!config.expired() && config.allows(S::SCOPE)).then_some(ApiKey {
+            name,
+            config,
+            scope: PhantomData,
+        })
+    }
+
+    /// Every key's configuration by name. Serializing it leaves out the secrets.
+    pub fn describe(&self) -> HashMap<&str, &KeyConfig> {
+        self.0
+            .iter()
+            .map(|(name, config)| (name.as_str(), config))
+            .collect()
+    }
+}
+
+/// A scope a route requires of its [`ApiKey`]
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub struct RenderScope;
+pub struct TemplatesScope;
+pub struct AdminScope;
+
+impl RequiredScope for RenderScope {
+    const SCOPE: Scope = Scope::Render;
+}
+
+impl RequiredScope for TemplatesScope {
+    const SCOPE: Scope = Scope::Templates;
+}
+
+impl RequiredScope for AdminScope {
+    const SCOPE: Scope = Scope::Admin;
+}
+
+/// The key a request was made with, which allows scope `S`
+pub struct ApiKey<'r, S = RenderScope> {
+    /// Name of the key in the configuration
+    pub name: &'r str,
+    config: &'r KeyConfig,
+    scope: PhantomData<S>,
+}
+
+impl<S> ApiKey<'_, S> {
+    /// The server's `limits` with this key's own applied
+    pub fn limits(&self, limits: Limits) -> Limits {
+        let own = &self.config.limits;
+        Limits {
+            max_request_bytes: limits.max_request_bytes,
+            max_resource_bytes: own.max_resource_bytes.unwrap_or(limits.max_resource_bytes),
+            max_resources: own.max_resources.unwrap_or(limits.max_resources),
+            max_width: own.max_width.unwrap_or(limits.max_width),
+            max_height: own.max_height.unwrap_or(limits.max_height),
+            max_pixels: own.max_pixels.unwrap_or(limits.max_pixels),
+            max_svg_nodes: own.max_svg_nodes.unwrap_or(limits.max_svg_nodes),
+        }
+    }
 
-pub struct ApiKey<'r>(&'r str);
+    /// Count `render` against this key's quotas, refusing it once they are used up.
+    /// Only a render that is delivered is counted, not one that failed for any reason.
+    pub async fn count_render<T>(
+        &self,
+        rates: &RateLimiter,
+        render: impl Future<Output = Result<T, ApiError>>,
+    ) -> Result<T, ApiError> {
+        rates.render(self.name, &self.config.limits)?;
+        let rendered = render.await;
+        rates.settle(self.name, rendered.is_ok());
+        rendered
+    }
 
-#[derive(Debug)]
+    /// Take a token for a url this key signed, false when there is none.
+    pub fn signed_request(&self, req: &Request<'_>, rates: &RateLimiter) -> bool {
+        rates.signed_request(req, self.name, &self.config.limits)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum ApiKeyError {
     Missing,
     Invalid,
+    Expired,
+    /// The key doesn't have the scope the route requires
+    Forbidden(Scope),
+    /// The key has no tokens left in its bucket
+    RateLimited,
+    /// The keys weren't configured
     Failure,
 }
 
-/// true if `key` is a valid API key string.
-fn is_valid(from_config: &str, key: &str) -> bool {
-    key == from_config
+impl ApiKeyError {
+    pub fn status(self) -> Status {
+        match self {
+            ApiKeyError::Missing => Status::Unauthorized,
+            ApiKeyError::Invalid | ApiKeyError::Expired | ApiKeyError::Forbidden(_) => {
+                Status::Forbidden
+            }
+            ApiKeyError::RateLimited => Status::TooManyRequests,
+            ApiKeyError::Failure => Status::InternalServerError,
+        }
+    }
+
+    /// The JSON body and headers this rejection is answered with
+    pub fn to_api_error(self) -> ApiError {
+        let status = self.status();
+        match self {
+            ApiKeyError::Missing => ApiError::new(
+                status,
+                "missing_key",
+                "send an api key as x-api-key or Authorization: Bearer",
+            )
+            .header("WWW-Authenticate", "Bearer realm=\"social-image\""),
+            ApiKeyError::Invalid => {
+                ApiError::new(status, "invalid_key", "the api key is not valid")
+            }
+            ApiKeyError::Expired => ApiError::new(status, "expired_key", "the api key has expired"),
+            ApiKeyError::Forbidden(scope) => ApiError::new(
+                status,
+                "insufficient_scope",
+                format!("the api key lacks the {} scope", scope.name()),
+            )
+            .with("scope", json!(scope)),
+            ApiKeyError::RateLimited => ApiError::new(
+                status,
+                "rate_limited",
+                "too many requests with this api key",
+            ),
+            ApiKeyError::Failure => {
+                ApiError::new(status, "key_config_error", "api keys are not configured")
+            }
+        }
+    }
+}
+
+/// Why the request's key was refused, kept for the catchers to answer with
+struct Rejection(Option<ApiKeyError>);
+
+/// The error for a request whose api key was refused, if it was
+pub fn rejection(req: &Request<'_>) -> Option<ApiError> {
+    req.local_cache(|| Rejection(None))
+        .0
+        .map(ApiKeyError::to_api_error)
+}
+
+fn reject<T>(req: &Request<'_>, error: ApiKeyError) -> Outcome<T, ApiKeyError> {
+    req.local_cache(|| Rejection(Some(error)));
+    Outcome::Error((error.status(), error))
+}
+
+/// The key from `x-api-key`, or else an `Authorization: Bearer` header
+fn presented<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    if let Some(key) = req.headers().get_one("x-api-key") {
+        return Some(key);
+    }
+    let authorization = req.headers().get_one("Authorization")?;
+    let (scheme, key) = authorization.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| key.trim())
 }
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for ApiKey<'r> {
+impl<'r, S: RequiredScope> FromRequest<'r> for ApiKey<'r, S> {
     type Error = ApiKeyError;
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let app_key = req
-            .rocket()
-            .state::<AppConfig>()
-            .map(|my_config| &my_config.key);
-        match app_key {
-            Some(api_key) => match req.headers().get_one("x-api-key") {
-                None => Outcome::Failure((Status::BadRequest, ApiKeyError::Missing)),
-                Some(key) if is_valid(api_key, key) => Outcome::Success(ApiKey(key)),
-                Some(_) => Outcome::Failure((Status::BadRequest, ApiKeyError::Invalid)),
-            },
-            None => {
-                error!("Failed to get config");
-                Outcome::Failure((Status::BadRequest, ApiKeyError::Failure))
-            }
+        let Some(keys) = req.rocket().state::<Keys>() else {
+            error!("Failed to get keys");
+            return reject(req, ApiKeyError::Failure);
+        };
+        let Some(key) = presented(req) else {
+            return reject(req, ApiKeyError::Missing);
+        };
+        let Some((name, config, secret_expired)) = keys.find(key) else {
+            return reject(req, ApiKeyError::Invalid);
+        };
+        if let TracingSpan(Some(span)) = req.local_cache(|| TracingSpan::<Option<Span>>(None)) {
+            span.record("api_key", name);
+        }
+        if config.expired() || secret_expired {
+            info!("Refused expired key {name}");
+            return reject(req, ApiKeyError::Expired);
+        }
+        if !config.allows(S::SCOPE) {
+            info!("Refused key {name} without scope {:?}", S::SCOPE);
+            return reject(req, ApiKeyError::Forbidden(S::SCOPE));
+        }
+        let Some(rates) = req.rocket().state::<RateLimiter>() else {
+            error!("Failed to get rate limiter");
+            return reject(req, ApiKeyError::Failure);
+        };
+        if !rates.key_request(req, name, &config.limits) {
+            info!("Rate limited key {name}");
+            return reject(req, ApiKeyError::RateLimited);
         }
+        Outcome::Success(ApiKey {
+            name,
+            config,
+            scope: PhantomData,
+        })
     }
 }
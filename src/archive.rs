@@ -0,0 +1,79 @@
+/*! Package several rendered variants into a single response body. */
+use crate::types::{ArchiveFormat, Result};
+
+use rocket::http::ContentType;
+use std::io::{Cursor, Write};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+/// One rendered file to include
+pub struct Entry {
+    pub name: String,
+    pub content_type: ContentType,
+    pub data: Vec<u8>,
+}
+
+/// Pack `entries` as `format`, returning the body and its content type.
+pub fn pack(format: ArchiveFormat, entries: &[Entry]) -> Result<(ContentType, Vec<u8>)> {
+    match format {
+        ArchiveFormat::Zip => Ok((ContentType::ZIP, zip(entries)?)),
+        ArchiveFormat::Multipart => {
+            // random, so no part can happen to contain it and be split in the wrong place
+            let boundary = format!(
+                "social-image-{}",
+                bs58::encode(rand::random::<[u8; 16]>()).into_string()
+            );
+            let body = multipart(entries, &boundary);
+            let params = ("boundary", boundary);
+            Ok((
+                ContentType::new("multipart", "mixed").with_params(params),
+                body,
+            ))
+        }
+    }
+}
+
+fn zip(entries: &[Entry]) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for entry in entries {
+        // images and pdfs are already compressed
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file(&entry.name, options)?;
+        zip.write_all(&entry.data)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+fn multipart(entries: &[Entry], boundary: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    for entry in entries {
+        body.extend_from_slice(
+            format!(
+                "--{boundary}\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\n\r\n",
+                entry.content_type, entry.name
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&entry.data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    body
+}
+
+/// A file name made of safe characters, unique among `taken`.
+pub fn file_name(stem: &str, extension: &str, taken: &[Entry]) -> String {
+    let stem: String = stem
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '-',
+        })
+        .collect();
+    let mut name = format!("{stem}.{extension}");
+    let mut n = 1;
+    while taken.iter().any(|e| e.name == name) {
+        n += 1;
+        name = format!("{stem}-{n}.{extension}");
+    }
+    name
+}
@@ -0,0 +1,485 @@
+/*! Keep rendered images on disk so identical requests are only rendered once.
+
+Entries are keyed by a SHA-256 over the svg, its resources, the template
+variables, the server's font library, the limits the render was held to and
+the output options, so any change to the input is a new entry.
+They expire `expire_png_secs` after being stored, and once the cache holds more
+than `cache_max_bytes` the least recently used are evicted. Either set to 0
+turns the cache off.
+*/
+use crate::fonts::{FontLibrary, FontResolution};
+use crate::types::{
+    ArchiveFormat, Background, Encoding, FitMode, Limits, Rendered, Result, Sizing, SvgPackage,
+    Target,
+};
+
+use rocket::http::ContentType;
+use rocket::serde::{json, Deserialize, Serialize};
+use rocket_prometheus::prometheus::{IntCounter, IntGauge, Registry};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use std::{fs as std_fs, io};
+use tokio::fs;
+
+/// Identifies one render's inputs
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    /// Hash `package` and `vars`, and `output` describing what is rendered from them.
+    pub fn new(
+        package: &SvgPackage,
+        vars: &HashMap<String, String>,
+        fonts: &FontLibrary,
+        limits: &Limits,
+        output: &impl CacheInput,
+    ) -> Self {
+        let mut hasher = Sha256::new();
+        // another version, or other fonts, may render the same input differently
+        hasher.update(env!("CARGO_PKG_VERSION"));
+        hasher.update(fonts.fingerprint());
+        let mut fields = Fields(hasher);
+        // a key with tighter limits mustn't be handed a render it would have been refused
+        limits.hash(&mut fields);
+        fields.field(&package.svg);
+        let mut resources: Vec<_> = package.resources.iter().collect();
+        resources.sort();
+        for (name, data) in resources {
+            fields.field(name.as_bytes());
+            fields.field(data);
+        }
+        let mut vars: Vec<_> = vars.iter().collect();
+        vars.sort();
+        for (name, value) in vars {
+            fields.field(name.as_bytes());
+            fields.field(value.as_bytes());
+        }
+        output.hash(&mut fields);
+
+        let mut hex = String::with_capacity(64);
+        for b in fields.0.finalize() {
+            let _ = write!(hex, "{b:02x}");
+        }
+        CacheKey(hex)
+    }
+}
+
+/// The hasher a [`CacheKey`] is built with
+pub struct Fields(Sha256);
+
+impl Fields {
+    /// Every field is length prefixed, so different inputs can't run together the same way
+    pub fn field(&mut self, bytes: &[u8]) {
+        self.0.update((bytes.len() as u64).to_le_bytes());
+        self.0.update(bytes);
+    }
+}
+
+/// An input to a render, hashed field by field into its [`CacheKey`].
+/// Unlike `Debug` output, what's hashed only changes when the render would.
+pub trait CacheInput {
+    fn hash(&self, fields: &mut Fields);
+}
+
+impl<T: CacheInput + ?Sized> CacheInput for &T {
+    fn hash(&self, fields: &mut Fields) {
+        (**self).hash(fields)
+    }
+}
+
+impl<T: CacheInput> CacheInput for Option<T> {
+    fn hash(&self, fields: &mut Fields) {
+        match self {
+            None => fields.field(b"none"),
+            Some(value) => {
+                fields.field(b"some");
+                value.hash(fields);
+            }
+        }
+    }
+}
+
+impl<A: CacheInput, B: CacheInput> CacheInput for (A, B) {
+    fn hash(&self, fields: &mut Fields) {
+        self.0.hash(fields);
+        self.1.hash(fields);
+    }
+}
+
+impl<T: CacheInput> CacheInput for [T] {
+    fn hash(&self, fields: &mut Fields) {
+        (self.len() as u64).hash(fields);
+        for item in self {
+            item.hash(fields);
+        }
+    }
+}
+
+impl<T: CacheInput> CacheInput for Vec<T> {
+    fn hash(&self, fields: &mut Fields) {
+        self.as_slice().hash(fields)
+    }
+}
+
+impl CacheInput for str {
+    fn hash(&self, fields: &mut Fields) {
+        fields.field(self.as_bytes())
+    }
+}
+
+impl CacheInput for String {
+    fn hash(&self, fields: &mut Fields) {
+        self.as_str().hash(fields)
+    }
+}
+
+impl CacheInput for u64 {
+    fn hash(&self, fields: &mut Fields) {
+        fields.field(&self.to_le_bytes())
+    }
+}
+
+impl CacheInput for u32 {
+    fn hash(&self, fields: &mut Fields) {
+        u64::from(*self).hash(fields)
+    }
+}
+
+impl CacheInput for f32 {
+    fn hash(&self, fields: &mut Fields) {
+        fields.field(&self.to_bits().to_le_bytes())
+    }
+}
+
+impl CacheInput for bool {
+    fn hash(&self, fields: &mut Fields) {
+        fields.field(&[u8::from(*self)])
+    }
+}
+
+impl CacheInput for Limits {
+    fn hash(&self, fields: &mut Fields) {
+        self.max_request_bytes.hash(fields);
+        self.max_resource_bytes.hash(fields);
+        (self.max_resources as u64).hash(fields);
+        self.max_width.hash(fields);
+        self.max_height.hash(fields);
+        self.max_pixels.hash(fields);
+        self.max_svg_nodes.hash(fields);
+    }
+}
+
+impl CacheInput for Target {
+    fn hash(&self, fields: &mut Fields) {
+        self.sizing.hash(fields);
+        self.encoding.hash(fields);
+    }
+}
+
+impl CacheInput for Sizing {
+    fn hash(&self, fields: &mut Fields) {
+        self.width.hash(fields);
+        self.height.hash(fields);
+        self.zoom.hash(fields);
+        let fit = match self.fit {
+            FitMode::Contain => "contain",
+            FitMode::Cover => "cover",
+            FitMode::Stretch => "stretch",
+            FitMode::Exact => "exact",
+        };
+        fit.hash(fields);
+    }
+}
+
+impl CacheInput for Encoding {
+    fn hash(&self, fields: &mut Fields) {
+        self.format.extension().hash(fields);
+        u32::from(self.quality).hash(fields);
+        self.lossless.hash(fields);
+        let background = self
+            .background
+            .map(|Background(c)| vec![c.red(), c.green(), c.blue(), c.alpha()]);
+        background.hash(fields);
+        self.max_bytes.map(|max| max as u64).hash(fields);
+    }
+}
+
+impl CacheInput for ArchiveFormat {
+    fn hash(&self, fields: &mut Fields) {
+        let format = match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Multipart => "multipart",
+        };
+        format.hash(fields);
+    }
+}
+
+impl AsRef<str> for CacheKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Stored before the image data in each entry's file
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Meta {
+    content_type: String,
+    fonts: Vec<FontResolution>,
+}
+
+struct Entry {
+    size: u64,
+    stored: SystemTime,
+    /// When this was last used, by `Index::clock`
+    used: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<CacheKey, Entry>,
+    /// Every entry by when it was last used, least recently first
+    by_use: BTreeMap<u64, CacheKey>,
+    bytes: u64,
+    clock: u64,
+}
+
+impl Index {
+    fn insert(&mut self, key: CacheKey, size: u64, stored: SystemTime) {
+        self.clock += 1;
+        let entry = Entry {
+            size,
+            stored,
+            used: self.clock,
+        };
+        self.by_use.insert(self.clock, key.clone());
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.by_use.remove(&old.used);
+            self.bytes -= old.size;
+        }
+        self.bytes += size;
+    }
+
+    /// Mark `key` as just used
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.clock += 1;
+            self.by_use.remove(&entry.used);
+            entry.used = self.clock;
+            self.by_use.insert(self.clock, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(old) = self.entries.remove(key) {
+            self.by_use.remove(&old.used);
+            self.bytes -= old.size;
+        }
+    }
+
+    /// Drop least recently used entries until at most `max_bytes` are kept
+    fn evict(&mut self, max_bytes: u64) -> Vec<CacheKey> {
+        let mut evicted = Vec::new();
+        while self.bytes > max_bytes {
+            let Some((_, oldest)) = self.by_use.first_key_value() else {
+                break;
+            };
+            let oldest = oldest.clone();
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+}
+
+/// Rendered images on disk under `dir`
+pub struct RenderCache {
+    dir: PathBuf,
+    ttl: Duration,
+    max_bytes: u64,
+    index: Mutex<Index>,
+    hits: IntCounter,
+    misses: IntCounter,
+    bytes: IntGauge,
+}
+
+impl RenderCache {
+    /// Open the cache in `dir`, picking up entries left by an earlier run,
+    /// and register its metrics with `registry`.
+    pub fn new(dir: &Path, ttl: Duration, max_bytes: u64, registry: &Registry) -> Result<Self> {
+        let hits = IntCounter::new(
+            "social_image_render_cache_hits_total",
+            "Renders served from the cache",
+        )?;
+        let misses = IntCounter::new(
+            "social_image_render_cache_misses_total",
+            "Renders not found in the cache",
+        )?;
+        let bytes = IntGauge::new(
+            "social_image_render_cache_bytes",
+            "Size of the rendered images in the cache",
+        )?;
+        registry.register(Box::new(hits.clone()))?;
+        registry.register(Box::new(misses.clone()))?;
+        registry.register(Box::new(bytes.clone()))?;
+
+        std_fs::create_dir_all(dir)?;
+        let mut found = Vec::new();
+        for file in std_fs::read_dir(dir)? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            let meta = file.metadata()?;
+            if name.ends_with(".tmp") {
+                // an interrupted write
+                let _ = std_fs::remove_file(file.path());
+                continue;
+            }
+            found.push((CacheKey(name), meta.len(), meta.modified()?));
+        }
+        // oldest first, so they are the first evicted
+        found.sort_by_key(|(_, _, stored)| *stored);
+        let mut index = Index::default();
+        for (key, size, stored) in found {
+            index.insert(key, size, stored);
+        }
+
+        let cache = RenderCache {
+            dir: dir.to_path_buf(),
+            ttl,
+            max_bytes,
+            index: Mutex::new(index),
+            hits,
+            misses,
+            bytes,
+        };
+        let evicted = cache.lock().evict(max_bytes);
+        for key in evicted {
+            let _ = std_fs::remove_file(cache.path(&key));
+        }
+        cache.bytes.set(cache.lock().bytes as i64);
+        Ok(cache)
+    }
+
+    fn enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_bytes > 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Index> {
+        self.index
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(&key.0)
+    }
+
+    /// The cached render for `key`, or else the result of `render`, which is cached for next time.
+    pub async fn get_or_render(
+        &self,
+        key: &CacheKey,
+        render: impl Future<Output = Result<Rendered>>,
+    ) -> Result<Rendered> {
+        if !self.enabled() {
+            return render.await;
+        }
+        if let Some(rendered) = self.get(key).await {
+            self.hits.inc();
+            return Ok(rendered);
+        }
+        self.misses.inc();
+        let rendered = render.await?;
+        if let Err(err) = self.put(key, &rendered).await {
+            warn!("Failed to cache render: {err}");
+        }
+        Ok(rendered)
+    }
+
+    async fn get(&self, key: &CacheKey) -> Option<Rendered> {
+        let expired = {
+            let mut index = self.lock();
+            let stored = index.entries.get(key)?.stored;
+            let expired = stored.elapsed().map_or(true, |age| age > self.ttl);
+            if !expired {
+                index.touch(key);
+            } else {
+                index.remove(key);
+                self.bytes.set(index.bytes as i64);
+            }
+            expired
+        };
+        if expired {
+            let _ = fs::remove_file(self.path(key)).await;
+            return None;
+        }
+
+        match fs::read(self.path(key))
+            .await
+            .and_then(|file| decode(&file))
+        {
+            Ok(rendered) => Some(rendered),
+            Err(err) => {
+                warn!("Dropping unreadable cache entry {}: {err}", key.0);
+                let _ = fs::remove_file(self.path(key)).await;
+                self.lock().remove(key);
+                None
+            }
+        }
+    }
+
+    async fn put(&self, key: &CacheKey, rendered: &Rendered) -> Result<()> {
+        let file = encode(rendered)?;
+        let size = file.len() as u64;
+        if size > self.max_bytes {
+            return Ok(());
+        }
+        let path = self.path(key);
+        let partial = path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+        fs::write(&partial, &file).await?;
+        fs::rename(&partial, &path).await?;
+
+        let evicted = {
+            let mut index = self.lock();
+            index.insert(key.clone(), size, SystemTime::now());
+            let evicted = index.evict(self.max_bytes);
+            self.bytes.set(index.bytes as i64);
+            evicted
+        };
+        for key in evicted {
+            let _ = fs::remove_file(self.path(&key)).await;
+        }
+        Ok(())
+    }
+}
+
+/// An entry's file: the JSON [`Meta`] on one line, then the image data
+fn encode(rendered: &Rendered) -> Result<Vec<u8>> {
+    let meta = Meta {
+        content_type: rendered.content_type.to_string(),
+        fonts: rendered.fonts.clone(),
+    };
+    let mut file = json::to_string(&meta)?.into_bytes();
+    file.push(b'\n');
+    file.extend_from_slice(&rendered.data);
+    Ok(file)
+}
+
+fn decode(file: &[u8]) -> io::Result<Rendered> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let split = file
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| invalid("no header"))?;
+    let meta: Meta = json::from_slice(&file[..split]).map_err(|_| invalid("bad header"))?;
+    Ok(Rendered {
+        content_type: ContentType::parse_flexible(&meta.content_type)
+            .ok_or_else(|| invalid("bad content type"))?,
+        data: file[split + 1..].to_vec(),
+        fonts: meta.fonts,
+    })
+}
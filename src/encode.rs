@@ -0,0 +1,175 @@
+/*! Turn a render into bytes in the requested format.
+
+Raster formats are encoded from a rendered pixmap. PDF skips rasterizing and
+converts the parsed tree directly, keeping vectors, text and images intact,
+and places it on a page sized and filled as a raster would be.
+*/
+use crate::types::{ApiError, Background, Encoding, OutputFormat, Result, Sizing};
+
+use eyre::eyre;
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref};
+use rocket::http::Status;
+use std::collections::HashMap;
+use svg2pdf::ConversionOptions;
+use tiny_skia::{Pixmap, PixmapPaint, Transform};
+use usvg::Tree;
+
+/// svg units are css pixels at 96 per inch, PDF's are points at 72,
+/// so the page prints at the intended size
+const POINTS_PER_PIXEL: f32 = 72.0 / 96.0;
+
+/// Encode `pixmap` according to `encoding`, lowering the quality of lossy
+/// formats as needed to come in under `encoding.max_bytes`.
+pub fn encode(pixmap: Pixmap, encoding: &Encoding) -> Result<Vec<u8>> {
+    let pixmap = match (encoding.format, encoding.background) {
+        // JPEG has no alpha channel, so always flatten onto something opaque
+        (OutputFormat::Jpeg, background) => {
+            flatten(&pixmap, background.unwrap_or_default().opaque())?
+        }
+        (_, Some(background)) => flatten(&pixmap, background)?,
+        (_, None) => pixmap,
+    };
+
+    let lossy = encoding.format.is_lossy(encoding.lossless);
+    let mut quality = encoding.quality;
+    loop {
+        let encoded = match encoding.format {
+            OutputFormat::Png => pixmap.encode_png()?,
+            OutputFormat::Jpeg => encode_jpeg(&pixmap, quality)?,
+            OutputFormat::WebP => encode_webp(&pixmap, quality, encoding.lossless),
+            OutputFormat::Pdf => return Err(eyre!("pdf is not a raster format")),
+        };
+        match fits(&encoded, encoding) {
+            Err(_) if lossy && quality > Encoding::MIN_QUALITY => {
+                quality = quality.saturating_sub(10).max(Encoding::MIN_QUALITY);
+                info!(
+                    "{} bytes is too large, retrying at quality {quality}",
+                    encoded.len()
+                );
+            }
+            result => return result.map(|_| encoded),
+        }
+    }
+}
+
+/// Convert `tree` to a single page PDF with fonts and images embedded.
+/// The page is the size `sizing` gives a raster, filled with the background.
+pub fn pdf(tree: &Tree, sizing: &Sizing, encoding: &Encoding) -> Result<Vec<u8>> {
+    let (page_size, transform) = sizing
+        .layout(tree.size())
+        .ok_or(eyre!("Invalid output size"))?;
+    let width = page_size.width() as f32 * POINTS_PER_PIXEL;
+    let height = page_size.height() as f32 * POINTS_PER_PIXEL;
+
+    let mut alloc = Ref::new(1);
+    let (catalog_id, page_tree_id, page_id, content_id) =
+        (alloc.bump(), alloc.bump(), alloc.bump(), alloc.bump());
+    let (chunk, svg_id) = svg2pdf::to_chunk(tree, ConversionOptions::default());
+    let mut ids = HashMap::new();
+    let chunk = chunk.renumber(|old| *ids.entry(old).or_insert_with(|| alloc.bump()));
+    let svg_name = Name(b"S1");
+
+    let mut content = Content::new();
+    if let Some(background) = encoding.background {
+        // without a blend group the fill can't be translucent
+        let color = background.opaque().0;
+        content
+            .set_fill_rgb(color.red(), color.green(), color.blue())
+            .rect(0.0, 0.0, width, height)
+            .fill_nonzero();
+    }
+    // the svg is drawn into a unit square, placed where the raster layout puts it,
+    // measuring from the page's bottom left in points
+    let svg_width = tree.size().width() * transform.sx * POINTS_PER_PIXEL;
+    let svg_height = tree.size().height() * transform.sy * POINTS_PER_PIXEL;
+    content
+        .transform([
+            svg_width,
+            0.0,
+            0.0,
+            svg_height,
+            transform.tx * POINTS_PER_PIXEL,
+            height - transform.ty * POINTS_PER_PIXEL - svg_height,
+        ])
+        .x_object(svg_name);
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id).kids([page_id]).count(1);
+    let mut page = pdf.page(page_id);
+    page.media_box(Rect::new(0.0, 0.0, width, height));
+    page.parent(page_tree_id);
+    page.contents(content_id);
+    page.resources().x_objects().pair(svg_name, ids[&svg_id]);
+    page.finish();
+    pdf.stream(content_id, &content.finish());
+    pdf.extend(&chunk);
+
+    let encoded = pdf.finish();
+    fits(&encoded, encoding)?;
+    Ok(encoded)
+}
+
+/// Fail if `encoded` is over the size limit in `encoding`.
+fn fits(encoded: &[u8], encoding: &Encoding) -> Result<()> {
+    match encoding.max_bytes {
+        Some(max) if encoded.len() > max => Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "too_large",
+            format!(
+                "output is {} bytes, over the limit of {max} bytes",
+                encoded.len()
+            ),
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Paint `pixmap` over a solid `background`.
+fn flatten(pixmap: &Pixmap, background: Background) -> Result<Pixmap> {
+    let mut flat =
+        Pixmap::new(pixmap.width(), pixmap.height()).ok_or(eyre!("Failed to allocate a pixmap"))?;
+    flat.fill(background.0);
+    flat.draw_pixmap(
+        0,
+        0,
+        pixmap.as_ref(),
+        &PixmapPaint::default(),
+        Transform::identity(),
+        None,
+    );
+    Ok(flat)
+}
+
+fn encode_jpeg(pixmap: &Pixmap, quality: u8) -> Result<Vec<u8>> {
+    let width = u16::try_from(pixmap.width()).map_err(|_| eyre!("too wide for jpeg"))?;
+    let height = u16::try_from(pixmap.height()).map_err(|_| eyre!("too tall for jpeg"))?;
+    let mut encoded = Vec::new();
+    // flattened, so every pixel is opaque and premultiplied equals straight alpha
+    jpeg_encoder::Encoder::new(&mut encoded, quality).encode(
+        pixmap.data(),
+        width,
+        height,
+        jpeg_encoder::ColorType::Rgba,
+    )?;
+    Ok(encoded)
+}
+
+fn encode_webp(pixmap: &Pixmap, quality: u8, lossless: bool) -> Vec<u8> {
+    let rgba: Vec<u8> = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    let encoder = webp::Encoder::from_rgba(&rgba, pixmap.width(), pixmap.height());
+    let encoded = if lossless {
+        encoder.encode_lossless()
+    } else {
+        encoder.encode(f32::from(quality))
+    };
+    encoded.to_vec()
+}
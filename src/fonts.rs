@@ -0,0 +1,260 @@
+/*! Font discovery and resolution for text rendering.
+
+`usvg` lays out text against a `fontdb::Database`. A [`FontLibrary`] is
+scanned once at launch from the configured font directories and shared by
+every render; fonts sent along with a request are layered on top of a copy of
+it for that render only.
+
+Fonts are recognised by their contents rather than by file name, so a
+resource uploaded as `brand.bin` is still picked up if it is really an
+OpenType font.
+*/
+use crate::AppConfig;
+use rocket::serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use usvg::{fontdb, FontResolver};
+
+/// Magic numbers at the start of TrueType, OpenType and font collection files.
+const FONT_MAGIC: [&[u8; 4]; 4] = [b"\x00\x01\x00\x00", b"OTTO", b"true", b"ttcf"];
+
+/// true if `data` looks like a ttf, otf, ttc or otc font.
+pub fn is_font(data: &[u8]) -> bool {
+    data.len() >= 4 && FONT_MAGIC.iter().any(|magic| data[..4] == magic[..])
+}
+
+/// Fonts available to every render, loaded once at launch.
+#[derive(Clone)]
+pub struct FontLibrary {
+    db: Arc<fontdb::Database>,
+    fingerprint: [u8; 32],
+}
+
+impl FontLibrary {
+    /// Scan the configured font directories (and system fonts, if enabled).
+    pub fn from_config(config: &AppConfig) -> Self {
+        let mut db = fontdb::Database::new();
+        if config.system_fonts {
+            db.load_system_fonts();
+        }
+        for dir in &config.font_dirs {
+            if !dir.is_dir() {
+                warn!("Font directory {dir:?} does not exist, skipping");
+                continue;
+            }
+            db.load_fonts_dir(dir);
+        }
+        if let Some(family) = &config.serif_family {
+            db.set_serif_family(family);
+        }
+        if let Some(family) = &config.sans_serif_family {
+            db.set_sans_serif_family(family);
+        }
+        if let Some(family) = &config.monospace_family {
+            db.set_monospace_family(family);
+        }
+        info!("Font library has {} face(s)", db.len());
+        FontLibrary {
+            fingerprint: fingerprint(&db),
+            db: Arc::new(db),
+        }
+    }
+
+    /// The shared database. Callers that need to add fonts should
+    /// `Arc::make_mut` it, which leaves the library itself untouched.
+    pub fn database(&self) -> Arc<fontdb::Database> {
+        Arc::clone(&self.db)
+    }
+
+    /// Changes whenever the faces or generic families do, so renders made
+    /// with another library aren't mistaken for this one's.
+    pub fn fingerprint(&self) -> &[u8; 32] {
+        &self.fingerprint
+    }
+
+    /// Describe every face in the library, sorted by family.
+    pub fn faces(&self) -> Vec<FontFace> {
+        let mut faces: Vec<FontFace> = self.db.faces().map(FontFace::from).collect();
+        faces.sort_by(|a, b| (&a.family, a.weight).cmp(&(&b.family, b.weight)));
+        faces
+    }
+
+    /// Which family each generic name maps to.
+    pub fn generic_families(&self) -> GenericFamilies {
+        GenericFamilies {
+            serif: self.db.family_name(&fontdb::Family::Serif).into(),
+            sans_serif: self.db.family_name(&fontdb::Family::SansSerif).into(),
+            monospace: self.db.family_name(&fontdb::Family::Monospace).into(),
+        }
+    }
+}
+
+/// Hash every face, where it was loaded from, and the generic families.
+/// Font files are known by their path, size and modification time rather
+/// than read again in full.
+fn fingerprint(db: &fontdb::Database) -> [u8; 32] {
+    let mut faces: Vec<String> = db
+        .faces()
+        .map(|face| {
+            let source = match &face.source {
+                fontdb::Source::Binary(data) => format!("{} bytes", (**data).as_ref().len()),
+                fontdb::Source::File(path) | fontdb::Source::SharedFile(path, _) => {
+                    let changed = std::fs::metadata(path).ok().map(|meta| {
+                        let modified = meta.modified().ok();
+                        let since = modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok());
+                        (meta.len(), since)
+                    });
+                    format!("{path:?} {changed:?}")
+                }
+            };
+            format!(
+                "{source} {} {:?} {} {:?} {:?} {:?}",
+                face.index,
+                face.families,
+                face.post_script_name,
+                face.style,
+                face.weight,
+                face.stretch
+            )
+        })
+        .collect();
+    faces.sort();
+    let mut hasher = Sha256::new();
+    for face in faces {
+        hasher.update(face);
+        hasher.update([0]);
+    }
+    for generic in [
+        fontdb::Family::Serif,
+        fontdb::Family::SansSerif,
+        fontdb::Family::Cursive,
+        fontdb::Family::Fantasy,
+        fontdb::Family::Monospace,
+    ] {
+        hasher.update(db.family_name(&generic));
+        hasher.update([0]);
+    }
+    hasher.finalize().into()
+}
+
+/// One face available to renders, as listed by `GET /fonts`.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FontFace {
+    pub family: String,
+    pub face: String,
+    pub style: &'static str,
+    pub weight: u16,
+    pub monospaced: bool,
+}
+
+impl From<&fontdb::FaceInfo> for FontFace {
+    fn from(info: &fontdb::FaceInfo) -> Self {
+        FontFace {
+            family: info
+                .families
+                .first()
+                .map(|f| f.0.clone())
+                .unwrap_or_default(),
+            face: info.post_script_name.clone(),
+            style: match info.style {
+                fontdb::Style::Normal => "normal",
+                fontdb::Style::Italic => "italic",
+                fontdb::Style::Oblique => "oblique",
+            },
+            weight: info.weight.0,
+            monospaced: info.monospaced,
+        }
+    }
+}
+
+/// The families used for the `serif`, `sans-serif` and `monospace` generic names.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct GenericFamilies {
+    pub serif: String,
+    pub sans_serif: String,
+    pub monospace: String,
+}
+
+/// Which face was picked for a `font-family` list found in the svg.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FontResolution {
+    /// The families requested by the svg, in order of preference.
+    pub requested: Vec<String>,
+
+    /// The family name of the face that was used, if any matched.
+    pub family: Option<String>,
+
+    /// The PostScript name of the face that was used, if any matched.
+    pub face: Option<String>,
+}
+
+impl FontResolution {
+    /// Render as a single header-safe line, e.g. `"Brand Sans", serif => Brand Sans (BrandSans-Bold)`.
+    pub fn to_header_value(&self) -> String {
+        let resolved = match (&self.family, &self.face) {
+            (Some(family), Some(face)) => format!("{family} ({face})"),
+            _ => "none".into(),
+        };
+        let line = format!("{} => {}", self.requested.join(", "), resolved);
+        line.chars()
+            .map(|c| {
+                if c.is_ascii() && !c.is_ascii_control() {
+                    c
+                } else {
+                    '?'
+                }
+            })
+            .collect()
+    }
+}
+
+/// Collects the resolutions made while parsing a single svg.
+#[derive(Clone, Default)]
+pub struct FontLog(Arc<Mutex<Vec<FontResolution>>>);
+
+impl FontLog {
+    /// Build a `FontResolver` that uses the default usvg selection, recording each decision here.
+    pub fn resolver(&self) -> FontResolver<'static> {
+        let log = self.clone();
+        let select = FontResolver::default_font_selector();
+        FontResolver {
+            select_font: Box::new(move |font, db| {
+                let id = select(font, db);
+                let face = id.and_then(|id| db.face(id));
+                log.record(FontResolution {
+                    requested: font.families().iter().map(ToString::to_string).collect(),
+                    family: face.and_then(|f| f.families.first()).map(|f| f.0.clone()),
+                    face: face.map(|f| f.post_script_name.clone()),
+                });
+                id
+            }),
+            ..FontResolver::default()
+        }
+    }
+
+    fn record(&self, resolution: FontResolution) {
+        let mut entries = self.0.lock().expect("font log poisoned");
+        if !entries.contains(&resolution) {
+            entries.push(resolution);
+        }
+    }
+
+    /// All distinct resolutions recorded so far.
+    pub fn entries(&self) -> Vec<FontResolution> {
+        self.0.lock().expect("font log poisoned").clone()
+    }
+}
+
+/// Load the font in `data` into `db`, logging how many faces it contained.
+pub fn load_font(db: &mut fontdb::Database, name: &str, data: Vec<u8>) {
+    let before = db.len();
+    db.load_font_data(data);
+    info!(
+        "Loaded {} font face(s) from resource {name:?}",
+        db.len() - before
+    );
+}
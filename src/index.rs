@@ -33,14 +33,70 @@ pub fn index() -> (ContentType, &'static str) {
                     <div class="py-4 sm:py-5 sm:grid sm:grid-cols-3 sm:gap-4 sm:px-6">
                         <dt class="font-medium text-gray-500"><code>POST /image</code></dt>
                         <dd class="mt-1 text-sm text-gray-900 sm:mt-0 sm:col-span-2 prose">
-                            <p>Post a SVG and it's resources in a supported format, and response body will contain the PNG.</p>
+                            <p>Post a SVG and it's resources in a supported format, and response body will contain the rendered image.</p>
                             <p>Submit files as <code>multipart/form-data</code>. The <code>svg</code> field contains the main svg to render, 
-                               and a series of <code>resources[name]</code> can also be sent for associated files like pngs or fonts.</p>
-                            <p>Output size is determined by the SVG's <code>width</code> and <code>height</code> attributes.</p>
+                               and a series of <code>resources[name]</code> can also be sent for associated files like pngs or fonts.
+                               A name may have directories, like <code>resources[img/logo.png]</code> for <code>href="img/logo.png"</code>,
+                               but may not be absolute, use <code>..</code> or be <code>main.svg</code>.</p>
+                            <p>Output size is determined by the SVG's <code>width</code> and <code>height</code> attributes, unless
+                               <code>width</code>, <code>height</code> or <code>zoom</code> fields are sent. With only one of width or height
+                               the other keeps the SVG's aspect ratio. With both, <code>fit</code> decides how the SVG fills the box, and
+                               any letterboxing is transparent or the <code>background</code> color. PDF pages are sized the same way, at 96 pixels to the inch.</p>
+                            <p>Uploads and renders are bounded by the server's limits. Too large an upload, resource or number of resources
+                               is <code>413</code>, and too large an output or too many SVG nodes is <code>422</code>, with the setting that was hit
+                               under <code>limit</code> and its value under <code>max</code>.
+                               A render that takes too long is abandoned with <code>504</code>, and when the server is too busy to
+                               queue another render the response is <code>503</code> with a <code>Retry-After</code> header.</p>
+                            <p>Any resource that is a TrueType or OpenType font (or collection) is loaded for this render only,
+                               whatever its name, alongside the fonts listed by <code>GET /fonts</code>. Each <code>font-family</code> used by the SVG is reported in an
+                               <code>X-Font-Resolution</code> response header naming the face that was picked, or <code>none</code>.</p>
+                            <p>The image is PNG, JPEG, WebP or PDF. Set the <code>format</code> field to <code>png</code>, <code>jpeg</code>,
+                               <code>webp</code> or <code>pdf</code>, or leave it out and the format is picked from the <code>Accept</code> header.
+                               PDF keeps the SVG as vectors with fonts and images embedded, for print.
+                               If nothing acceptable can be produced the response is <code>406</code>.
+                               Sending the same SVG, resources and fields again returns the earlier render from a cache.</p>
+                            <p>The SVG can be a template: <code>{{title}}</code> is replaced by the <code>vars[title]</code> field,
+                               and <code>{{title|Untitled}}</code> falls back to <code>Untitled</code> when the field isn't sent.
+                               Values are XML escaped. If any placeholder has no value and no default the response is <code>422</code>
+                               with the names listed under <code>missing</code>.</p>
+                            <p>Text can wrap to fit a box: give a <code>&lt;text&gt;</code> a <code>data-fit-width</code> and its words are
+                               broken into lines no wider than that. <code>data-max-lines</code> and <code>data-fit-height</code> limit the lines;
+                               to stay within them the font size shrinks down to <code>data-min-font-size</code>, and whatever still doesn't fit
+                               ends in an ellipsis. <code>data-line-height</code> sets line spacing as a multiple of the font size (default 1.2).
+                               Lines are measured with the fonts used for the render.</p>
+                            <p>To get several sizes or formats from one SVG, add <code>variants</code>. Each variant takes the fields
+                               below plus a <code>name</code>, e.g. <code>variants[0].preset=og</code>, <code>variants[1].format=webp</code>;
+                               anything it leaves out comes from the top level fields. The SVG is parsed once and every variant is returned
+                               in a ZIP, or as <code>multipart/mixed</code> if <code>archive=multipart</code> or the <code>Accept</code> header asks for it.
+                               Files are named after the variant's <code>name</code>, else its preset.</p>
+                            <h5 class="text-sm font-medium text-gray-500">Fields</h5>
+                            <ul>
+                                <li><code>vars[name]</code>: optional. Value for the <code>{{name}}</code> placeholders in the SVG.</li>
+                                <li><code>preset</code>: optional. A platform preset setting size, fit, format and maximum file size:
+                                    <code>og</code> (1200x630), <code>twitter</code> (1200x675), <code>twitter-summary</code> (800x800),
+                                    <code>linkedin</code> (1200x627), <code>instagram-square</code> (1080x1080 JPEG), or any configured on the server.
+                                    Other fields override the preset, and a <code>width</code> or <code>height</code> replaces its whole size,
+                                    so sending just one keeps the SVG's aspect ratio.</li>
+                                <li><code>format</code>: optional. <code>png</code>, <code>jpeg</code>, <code>webp</code> or <code>pdf</code>.</li>
+                                <li><code>quality</code>: optional. 1-100 for JPEG and lossy WebP (default 85).</li>
+                                <li><code>lossless</code>: optional. <code>true</code> for lossless WebP.</li>
+                                <li><code>background</code>: optional. A css color to flatten the image onto. JPEG is always flattened, onto white by default, and any transparency in the color shows white.</li>
+                                <li><code>width</code>, <code>height</code>: optional. Output size in pixels.</li>
+                                <li><code>zoom</code>: optional. Multiplies the output size, e.g. <code>2</code> for a 2x image.</li>
+                                <li><code>fit</code>: optional. <code>contain</code> (default), <code>cover</code>, <code>stretch</code> or <code>exact</code>.</li>
+                                <li><code>variants[n]</code>: optional. Outputs to render, each with its own <code>name</code> and any of the fields above.</li>
+                                <li><code>archive</code>: optional. <code>zip</code> or <code>multipart</code> when rendering variants.</li>
+                            </ul>
                             <h5 class="text-sm font-medium text-gray-500">Headers</h5>
                             <ul>
                                 <li>
-                                    <code>X-API-KEY</code>: required. Key to access the service.
+                                    <code>X-API-KEY</code>: required. Key to access the service, or send it as <code>Authorization: Bearer &lt;key&gt;</code>.
+                                    Without a key the response is <code>401</code>; with an unknown, expired or under-scoped one, <code>403</code>.
+                                    A key over its rate or render quota gets a <code>429</code> with <code>Retry-After</code>.
+                                </li>
+                                <li>
+                                    <code>Accept</code>: optional. <code>image/png</code>, <code>image/jpeg</code>, <code>image/webp</code> or <code>application/pdf</code>.
+                                    With variants, <code>application/zip</code> or <code>multipart/mixed</code>.
                                 </li>
                             </ul>
                             <h5 class="text-sm font-medium text-gray-500">Example</h5>
@@ -51,6 +107,122 @@ pub fn index() -> (ContentType, &'static str) {
     -F resources[greatfont-Regular.otf]=@greatfont-Regular.otf\
     http://localhost:8000/image \
     --output test.png</code></pre>
+                            <p>The same fields can be sent as <code>application/json</code> instead, with the <code>svg</code> as a string,
+                               <code>vars</code> and <code>variants</code> as an object and an array, and each resource as
+                               <code>{"base64": "..."}</code> or as <code>{"template": {"id": "...", "name": "img/logo.png"}}</code>
+                               to use a resource of a stored template. <code>name</code> defaults to the resource's own name.</p>
+                            <pre><code>curl -X POST \
+    -H "x-api-key: Your-Private-Key" \
+    -H "content-type: application/json" \
+    -d '{"svg": "&lt;svg ...&gt;", "resources": {"logo.png": {"base64": "iVBORw0KGgo..."}}, "vars": {"title": "Hi"}, "preset": "og"}' \
+    http://localhost:8000/image \
+    --output test.png</code></pre>
+                        </dd>
+                    </div>
+                    <div class="py-4 sm:py-5 sm:grid sm:grid-cols-3 sm:gap-4 sm:px-6">
+                        <dt class="font-medium text-gray-500"><code>GET /fonts</code></dt>
+                        <dd class="mt-1 text-sm text-gray-900 sm:mt-0 sm:col-span-2 prose">
+                            <p>List the fonts preloaded from the server's font directories, and which families
+                               <code>serif</code>, <code>sans-serif</code> and <code>monospace</code> map to.
+                               These can be used by any SVG without uploading them.</p>
+                            <h5 class="text-sm font-medium text-gray-500">Headers</h5>
+                            <ul>
+                                <li>
+                                    <code>X-API-KEY</code>: required. Key to access the service.
+                                </li>
+                            </ul>
+                        </dd>
+                    </div>
+                    <div class="py-4 sm:py-5 sm:grid sm:grid-cols-3 sm:gap-4 sm:px-6">
+                        <dt class="font-medium text-gray-500"><code>POST /templates</code>, <code>PUT /templates/&lt;id&gt;</code></dt>
+                        <dd class="mt-1 text-sm text-gray-900 sm:mt-0 sm:col-span-2 prose">
+                            <p>Store an SVG and its resources as a template, so they don't need to be sent with every render.
+                               Send the <code>svg</code> and <code>resources[name]</code> fields as for <code>POST /image</code>.
+                               <code>POST</code> creates a template and responds <code>201</code> with its location;
+                               <code>PUT</code> replaces the files of an existing one, keeping its id.</p>
+                            <p>The response describes the template: its <code>id</code>, total <code>size</code> in bytes,
+                               <code>resources</code> names, and <code>created</code> and <code>updated</code> times.</p>
+                            <h5 class="text-sm font-medium text-gray-500">Headers</h5>
+                            <ul>
+                                <li>
+                                    <code>X-API-KEY</code>: required. Key to access the service.
+                                </li>
+                            </ul>
+                        </dd>
+                    </div>
+                    <div class="py-4 sm:py-5 sm:grid sm:grid-cols-3 sm:gap-4 sm:px-6">
+                        <dt class="font-medium text-gray-500"><code>GET /templates</code>, <code>GET /templates/&lt;id&gt;</code></dt>
+                        <dd class="mt-1 text-sm text-gray-900 sm:mt-0 sm:col-span-2 prose">
+                            <p>Describe every stored template, oldest first, or just one.</p>
+                            <h5 class="text-sm font-medium text-gray-500">Headers</h5>
+                            <ul>
+                                <li>
+                                    <code>X-API-KEY</code>: required. Key to access the service.
+                                </li>
+                            </ul>
+                        </dd>
+                    </div>
+                    <div class="py-4 sm:py-5 sm:grid sm:grid-cols-3 sm:gap-4 sm:px-6">
+                        <dt class="font-medium text-gray-500"><code>GET /templates/&lt;id&gt;/render.png</code></dt>
+                        <dd class="mt-1 text-sm text-gray-900 sm:mt-0 sm:col-span-2 prose">
+                            <p>Render a stored template from a plain url, e.g. for an <code>og:image</code> tag.
+                               Every query string parameter fills the template variable of the same name, so
+                               <code>render.png?title=Hello</code> replaces <code>{{title}}</code>.
+                               Use <code>render.jpg</code>, <code>render.webp</code> or <code>render.pdf</code> for other formats.</p>
+                            <p>Responses carry an <code>ETag</code>, <code>Last-Modified</code>, <code>Cache-Control</code> and <code>Expires</code>,
+                               <code>private</code> when made with a key and <code>public</code> for signed urls,
+                               and a request with a matching <code>If-None-Match</code> or <code>If-Modified-Since</code> gets a <code>304</code>.</p>
+                            <p>Urls minted by <code>POST /templates/&lt;id&gt;/sign</code> carry a <code>sig</code> parameter
+                               and work without the key, so they can go in public pages.</p>
+                            <h5 class="text-sm font-medium text-gray-500">Headers</h5>
+                            <ul>
+                                <li>
+                                    <code>X-API-KEY</code>: required unless the url is signed. Key to access the service.
+                                </li>
+                            </ul>
+                        </dd>
+                    </div>
+                    <div class="py-4 sm:py-5 sm:grid sm:grid-cols-3 sm:gap-4 sm:px-6">
+                        <dt class="font-medium text-gray-500"><code>POST /templates/&lt;id&gt;/sign</code></dt>
+                        <dd class="mt-1 text-sm text-gray-900 sm:mt-0 sm:col-span-2 prose">
+                            <p>Mint a signed render url for a template. Post JSON like
+                               <code>{"vars": {"title": "Hello"}, "format": "png", "expires_in": 86400}</code>;
+                               every field is optional, and without <code>expires_in</code> the url never expires.
+                               The response has the <code>url</code> path to append to this service's address, and its <code>expires</code> unix time.
+                               Changing any parameter of a signed url, or using it after it expires, gets a <code>403</code>.
+                               Signed urls may be rate limited per client address, answering <code>429</code>.</p>
+                            <h5 class="text-sm font-medium text-gray-500">Headers</h5>
+                            <ul>
+                                <li>
+                                    <code>X-API-KEY</code>: required. Key to access the service.
+                                </li>
+                            </ul>
+                        </dd>
+                    </div>
+                    <div class="py-4 sm:py-5 sm:grid sm:grid-cols-3 sm:gap-4 sm:px-6">
+                        <dt class="font-medium text-gray-500"><code>DELETE /templates/&lt;id&gt;</code></dt>
+                        <dd class="mt-1 text-sm text-gray-900 sm:mt-0 sm:col-span-2 prose">
+                            <p>Delete a stored template. Responds <code>204</code>, or <code>404</code> if there is no such template.</p>
+                            <h5 class="text-sm font-medium text-gray-500">Headers</h5>
+                            <ul>
+                                <li>
+                                    <code>X-API-KEY</code>: required. Key to access the service.
+                                </li>
+                            </ul>
+                        </dd>
+                    </div>
+                    <div class="py-4 sm:py-5 sm:grid sm:grid-cols-3 sm:gap-4 sm:px-6">
+                        <dt class="font-medium text-gray-500"><code>GET /keys</code></dt>
+                        <dd class="mt-1 text-sm text-gray-900 sm:mt-0 sm:col-span-2 prose">
+                            <p>The configured API keys by name, with their <code>scopes</code>, <code>expires</code> and <code>limits</code> but never their secrets.
+                               Keys with the <code>render</code> scope may render and sign urls, <code>templates</code> may manage stored templates,
+                               and <code>admin</code> may do both and use this route. Other keys get a <code>403</code>.</p>
+                            <h5 class="text-sm font-medium text-gray-500">Headers</h5>
+                            <ul>
+                                <li>
+                                    <code>X-API-KEY</code>: required. A key with the <code>admin</code> scope.
+                                </li>
+                            </ul>
                         </dd>
                     </div>
                 </dl>
@@ -36,7 +36,7 @@ impl<'r> FromRequest<'r> for RequestId {
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
         match request.local_cache(|| RequestId::<Option<String>>(None)) {
             RequestId(Some(request_id)) => Outcome::Success(RequestId(request_id.to_owned())),
-            RequestId(None) => Outcome::Failure((Status::InternalServerError, ())),
+            RequestId(None) => Outcome::Error((Status::InternalServerError, ())),
         }
     }
 }
@@ -71,7 +71,8 @@ impl Fairing for TracingFairing {
             http.uri = %req.uri().path(),
             http.user_agent=%user_agent,
             http.status_code = tracing::field::Empty,
-            http.request_id=%request_id
+            http.request_id=%request_id,
+            api_key = tracing::field::Empty
         );
 
         req.local_cache(|| TracingSpan::<Option<Span>>(Some(span)));
@@ -84,7 +85,7 @@ impl Fairing for TracingFairing {
             .to_owned()
         {
             let _entered_span = span.entered();
-            _entered_span.record("http.status_code", &res.status().code);
+            _entered_span.record("http.status_code", res.status().code);
 
             if let Some(request_id) = &req.local_cache(|| RequestId::<Option<String>>(None)).0 {
                 info!("Returning request {} with {}", request_id, res.status());
@@ -107,7 +108,7 @@ impl<'r> FromRequest<'r> for TracingSpan {
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
         match request.local_cache(|| TracingSpan::<Option<Span>>(None)) {
             TracingSpan(Some(span)) => Outcome::Success(TracingSpan(span.to_owned())),
-            TracingSpan(None) => Outcome::Failure((Status::InternalServerError, ())),
+            TracingSpan(None) => Outcome::Error((Status::InternalServerError, ())),
         }
     }
 }
@@ -1,65 +1,376 @@
-use crate::types::SvgDescription;
+use crate::apikey::{AdminScope, KeyConfig, Keys};
+use crate::cache::{CacheKey, RenderCache};
+use crate::fonts::FontLibrary;
+use crate::pool::RenderPool;
+use crate::presets::{Preset, Presets};
+use crate::ratelimit::{RateLimitHeaders, RateLimiter};
+use crate::render::Renderer;
+use crate::types::{
+    ApiError, ArchiveFormat, Cacheable, FileStore, Limits, OutputFormat, RenderRequest, Rendered,
+    Store, SvgDescription, SvgJson, SvgPackage, Target, Validators,
+};
 use figment::{
     providers::{Env, Format, Serialized, Toml},
     Figment, Profile,
 };
-use std::{env, path, result};
+use std::{collections::HashMap, env, path, result, time::Duration};
+use time::OffsetDateTime;
 use tokio::fs;
 
 use rocket::{
     fairing::AdHoc,
-    form::Form,
-    http::{ContentType, Status},
+    form::{self, Form},
+    http::{Accept, Status},
     serde::{
-        json::{json, Value},
+        json::{self, json, Json, Value},
         Deserialize, Serialize,
     },
-    Request,
+    Build, Request, Rocket, State,
 };
 
 #[macro_use]
 extern crate rocket;
 
 mod apikey;
+mod archive;
+mod cache;
+mod encode;
+mod fonts;
 mod index;
 mod instrumentation;
+mod pool;
+mod presets;
+mod ratelimit;
 mod render;
+mod signed;
+mod template;
+mod templates;
 #[cfg(test)]
 mod tests;
+mod text;
 mod types;
 
 #[post("/image", format = "multipart/form-data", data = "<svg_form>")]
+#[allow(clippy::too_many_arguments)]
 async fn render_svg(
-    svg_form: Form<SvgDescription<'_>>,
-    _api_key: apikey::ApiKey<'_>,
-) -> result::Result<(ContentType, Vec<u8>), Status> {
-    let result = render::png_from_svg(svg_form.into_inner())
+    svg_form: result::Result<Form<SvgDescription<'_>>, form::Errors<'_>>,
+    accept: Option<&Accept>,
+    fonts: &State<FontLibrary>,
+    presets: &State<Presets>,
+    cache: &State<RenderCache>,
+    renderer: &State<Renderer>,
+    config: &State<AppConfig>,
+    rates: &State<RateLimiter>,
+    api_key: apikey::ApiKey<'_>,
+) -> result::Result<Cacheable, ApiError> {
+    let limits = api_key.limits(config.limits());
+    let mut svg_form = limits.form(svg_form)?;
+    let request = (svg_form.render_request(&limits).await).map_err(ApiError::from_report)?;
+    let render = render_request(
+        request, accept, fonts, presets, cache, renderer, config, &limits,
+    );
+    api_key.count_render(rates, render).await
+}
+
+/// `POST /image` with the svg and resources in a JSON body
+#[post("/image", format = "json", data = "<body>")]
+#[allow(clippy::too_many_arguments)]
+async fn render_json(
+    body: result::Result<Json<SvgJson>, json::Error<'_>>,
+    accept: Option<&Accept>,
+    fonts: &State<FontLibrary>,
+    presets: &State<Presets>,
+    store: &State<Box<dyn Store>>,
+    cache: &State<RenderCache>,
+    renderer: &State<Renderer>,
+    config: &State<AppConfig>,
+    rates: &State<RateLimiter>,
+    api_key: apikey::ApiKey<'_>,
+) -> result::Result<Cacheable, ApiError> {
+    let limits = api_key.limits(config.limits());
+    let body = limits.json(body)?.into_inner();
+    let request =
+        (body.render_request(store.as_ref(), &limits).await).map_err(ApiError::from_report)?;
+    let render = render_request(
+        request, accept, fonts, presets, cache, renderer, config, &limits,
+    );
+    api_key.count_render(rates, render).await
+}
+
+/// Render a request's one output, or its variants into an archive
+#[allow(clippy::too_many_arguments)]
+async fn render_request(
+    request: RenderRequest,
+    accept: Option<&Accept>,
+    fonts: &FontLibrary,
+    presets: &Presets,
+    cache: &RenderCache,
+    renderer: &Renderer,
+    config: &AppConfig,
+    limits: &Limits,
+) -> result::Result<Cacheable, ApiError> {
+    let RenderRequest {
+        package,
+        vars,
+        defaults,
+        variants,
+        archive,
+    } = request;
+    let vars = &vars;
+    if !variants.is_empty() {
+        let format = match archive {
+            Some(format) => format,
+            None => ArchiveFormat::negotiate(accept).ok_or_else(|| {
+                not_acceptable("can only produce application/zip or multipart/mixed")
+            })?,
+        };
+        // resolve everything before doing any work, so a bad variant fails fast
+        let targets = variants
+            .into_iter()
+            .enumerate()
+            .map(|(i, variant)| {
+                let variant = variant.or(&defaults);
+                let stem = (variant.name.clone())
+                    .or_else(|| variant.preset.clone())
+                    .unwrap_or_else(|| format!("variant-{}", i + 1));
+                Ok((stem, variant.resolve(presets, || Ok(OutputFormat::Png))?))
+            })
+            .collect::<result::Result<Vec<_>, ApiError>>()?;
+        let key = CacheKey::new(&package, vars, fonts, limits, &(format, &targets));
+        let rendered = cache
+            .get_or_render(
+                &key,
+                render_variants(&package, vars, &targets, format, fonts, limits, renderer),
+            )
+            .await
+            .map_err(ApiError::from_report)?;
+        return Ok(Cacheable {
+            validators: config.validators(&key, None, true),
+            rendered: Some(rendered),
+        });
+    }
+
+    let target = defaults.resolve(presets, || {
+        OutputFormat::negotiate(accept).ok_or_else(|| {
+            not_acceptable("can only produce image/png, image/jpeg, image/webp or application/pdf")
+        })
+    })?;
+    let key = CacheKey::new(&package, vars, fonts, limits, &target);
+    let rendered = cache
+        .get_or_render(
+            &key,
+            render::image_from_svg(&package, vars, &target, fonts, limits, renderer),
+        )
+        .await
+        .map_err(ApiError::from_report)?;
+    Ok(Cacheable {
+        validators: config.validators(&key, None, true),
+        rendered: Some(rendered),
+    })
+}
+
+/// Parse once and render every target into an archive.
+async fn render_variants(
+    package: &SvgPackage,
+    vars: &HashMap<String, String>,
+    targets: &[(String, Target)],
+    format: ArchiveFormat,
+    fonts: &FontLibrary,
+    limits: &Limits,
+    renderer: &Renderer,
+) -> types::Result<Rendered> {
+    renderer
+        .timed(|deadline| async move {
+            let parsed =
+                render::parse_svg(package, vars, fonts, limits, renderer, deadline).await?;
+            let mut entries: Vec<archive::Entry> = Vec::with_capacity(targets.len());
+            for (stem, target) in targets {
+                let format = target.encoding.format;
+                let entry = archive::Entry {
+                    name: archive::file_name(stem, format.extension(), &entries),
+                    content_type: format.content_type(),
+                    data: render::render(&parsed, target, renderer).await?,
+                };
+                entries.push(entry);
+            }
+            let (content_type, data) = archive::pack(format, &entries)?;
+            Ok(Rendered {
+                content_type,
+                data,
+                fonts: parsed.fonts.entries(),
+            })
+        })
         .await
-        .map_err(|e| {
-            error!("Error while rendering: {e:?}");
-            Status::InternalServerError
-        })?;
-    Ok((ContentType::PNG, result))
+}
+
+fn not_acceptable(message: &str) -> ApiError {
+    ApiError::new(Status::NotAcceptable, "not_acceptable", message)
+}
+
+#[get("/fonts")]
+fn list_fonts(fonts: &State<FontLibrary>, _api_key: apikey::ApiKey<'_>) -> Json<Value> {
+    Json(json!({
+        "generic": fonts.generic_families(),
+        "faces": fonts.faces(),
+    }))
+}
+
+/// The configured api keys, without their secrets
+#[get("/keys")]
+fn list_keys(keys: &State<Keys>, _api_key: apikey::ApiKey<'_, AdminScope>) -> Json<Value> {
+    Json(json!(keys.describe()))
 }
 
 #[derive(Deserialize, Serialize)]
 struct AppConfig {
-    key: String,
+    /// A key with every scope, alongside `keys`
+    key: Option<String>,
+    /// Named api keys
+    keys: HashMap<String, KeyConfig>,
+    /// Secret for signed urls, `key` when missing
+    signing_key: Option<String>,
     temp_path: path::PathBuf,
+    /// Seconds a render stays in the cache
+    expire_png_secs: u64,
+    /// Largest total size of the render cache, in bytes
+    cache_max_bytes: u64,
+    /// Seconds clients and CDNs may reuse a render, `expire_png_secs` when missing
+    max_age_secs: Option<u64>,
+    /// Directory where uploaded templates are kept
+    store: path::PathBuf,
+    /// Directories scanned once at launch for fonts available to every render
+    font_dirs: Vec<path::PathBuf>,
+    /// Also load the fonts installed on the host
+    system_fonts: bool,
+    serif_family: Option<String>,
+    sans_serif_family: Option<String>,
+    monospace_family: Option<String>,
+    /// Presets added to, or replacing, the built in ones
+    presets: HashMap<String, Preset>,
+    /// Largest request body, in bytes
+    max_request_bytes: u64,
+    /// Largest single resource, in bytes
+    max_resource_bytes: u64,
+    /// Most resources one request may upload
+    max_resources: usize,
+    /// Largest raster output, in pixels
+    max_width: u32,
+    max_height: u32,
+    max_pixels: u64,
+    /// Most XML nodes an svg may have
+    max_svg_nodes: u32,
+    /// Seconds a request's rendering may take before it is abandoned, 0 for no limit
+    render_timeout_secs: f32,
+    /// Packages up to this many bytes render without writing their resources to disk
+    memory_render_max_bytes: u64,
+    /// Threads rendering at once, the number of CPUs when missing
+    render_threads: Option<usize>,
+    /// Renders that may wait for a render thread before more are refused
+    render_queue: usize,
+    /// Requests each api key may make per second, on average. 0 for no limit.
+    rate_per_sec: f64,
+    /// Requests an api key may make at once, before being held to `rate_per_sec`
+    rate_burst: u32,
+    /// The same, for signed urls from each client IP
+    signed_rate_per_sec: f64,
+    signed_rate_burst: u32,
+    /// Renders each api key may request per UTC day
+    daily_quota: Option<u64>,
+    /// Renders each api key may request per UTC month
+    monthly_quota: Option<u64>,
 }
 
 impl Default for AppConfig {
     fn default() -> AppConfig {
         AppConfig {
-            key: "default".into(),
+            key: None,
+            keys: HashMap::new(),
+            signing_key: None,
             temp_path: "/tmp".into(),
+            expire_png_secs: 24 * 60 * 60,
+            cache_max_bytes: 256 * 1024 * 1024,
+            max_age_secs: None,
+            store: "store".into(),
+            font_dirs: Vec::new(),
+            system_fonts: false,
+            serif_family: None,
+            sans_serif_family: None,
+            monospace_family: None,
+            presets: HashMap::new(),
+            max_request_bytes: 16 * 1024 * 1024,
+            max_resource_bytes: 8 * 1024 * 1024,
+            max_resources: 64,
+            max_width: 8192,
+            max_height: 8192,
+            max_pixels: 32 * 1024 * 1024,
+            max_svg_nodes: 100_000,
+            render_timeout_secs: 30.0,
+            memory_render_max_bytes: 4 * 1024 * 1024,
+            render_threads: None,
+            render_queue: 64,
+            rate_per_sec: 0.0,
+            rate_burst: 10,
+            signed_rate_per_sec: 0.0,
+            signed_rate_burst: 10,
+            daily_quota: None,
+            monthly_quota: None,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Caching headers for a render of `key`
+    /// `private` for renders made with an api key, which shared caches mustn't hand to others.
+    fn validators(
+        &self,
+        key: &CacheKey,
+        last_modified: Option<OffsetDateTime>,
+        private: bool,
+    ) -> Validators {
+        Validators {
+            etag: key.as_ref().to_string(),
+            last_modified,
+            max_age: self.max_age_secs.unwrap_or(self.expire_png_secs),
+            private,
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            max_request_bytes: self.max_request_bytes,
+            max_resource_bytes: self.max_resource_bytes,
+            max_resources: self.max_resources,
+            max_width: self.max_width,
+            max_height: self.max_height,
+            max_pixels: self.max_pixels,
+            max_svg_nodes: self.max_svg_nodes,
         }
     }
 }
 
+/// The error for a request whose api key or signed url was refused, if it was
+fn rejection(req: &Request) -> Option<ApiError> {
+    apikey::rejection(req).or_else(|| signed::rejection(req))
+}
+
 #[catch(500)]
-fn internal_error() -> Value {
-    json!({"error": "internal_error"})
+fn internal_error(req: &Request) -> result::Result<ApiError, Value> {
+    rejection(req).ok_or_else(|| json!({"error": "internal_error"}))
+}
+
+#[catch(401)]
+fn unauthorized(req: &Request) -> result::Result<ApiError, Value> {
+    rejection(req).ok_or_else(|| default(Status::Unauthorized, req))
+}
+
+#[catch(429)]
+fn too_many_requests(req: &Request) -> ApiError {
+    rejection(req).unwrap_or_else(ratelimit::too_many_requests)
+}
+
+#[catch(403)]
+fn forbidden(req: &Request) -> result::Result<ApiError, Value> {
+    rejection(req).ok_or_else(|| default(Status::Forbidden, req))
 }
 
 #[catch(404)]
@@ -72,10 +383,24 @@ fn default(status: Status, _req: &Request) -> Value {
     json!({"status": status.code, "reason": status.reason() })
 }
 
-#[launch]
-async fn rocket() -> _ {
-    color_eyre::install().unwrap();
+#[rocket::main]
+async fn main() -> eyre::Result<()> {
+    // `social-image keygen` prints a new api key and the hash to configure it with
+    if env::args().nth(1).as_deref() == Some("keygen") {
+        let (key, hash) = apikey::generate();
+        println!("key:  {key}\nhash: {hash}");
+        return Ok(());
+    }
+    rocket().await.launch().await?;
+    Ok(())
+}
+
+async fn rocket() -> Rocket<Build> {
+    // a hook is already installed if an error was reported first, e.g. in tests
+    let _ = color_eyre::install();
     let figment = Figment::from(rocket::Config::default())
+        // a client can send any X-Real-IP, so only trust a header once one is configured
+        .merge(("ip_header", false))
         .merge(Serialized::defaults(AppConfig::default()))
         .merge(Toml::file("App.toml").nested())
         .merge(Env::prefixed("APP_").global())
@@ -83,22 +408,98 @@ async fn rocket() -> _ {
 
     instrumentation::init_logging();
 
+    let config: AppConfig = figment.extract().expect("config");
+    // rocket stops reading a form at its limits, so every upload is bounded by
+    // max_request_bytes. Resources are checked against max_resource_bytes after.
+    let figment = figment
+        .merge(("limits.data-form", config.max_request_bytes))
+        .merge(("limits.file", config.max_request_bytes))
+        .merge(("limits.json", config.max_request_bytes));
+
     let rocket = rocket::custom(figment);
 
     let prometheus = rocket_prometheus::PrometheusMetrics::new();
 
-    let config: AppConfig = rocket.figment().extract().expect("config");
+    // font directories and the store are relative to where we were launched, so load before moving to temp_path
+    let fonts = FontLibrary::from_config(&config);
+    let presets = Presets::new(&config.presets);
+    let keys = Keys::from_config(&config).expect("invalid key. check config");
+    if !signed::enabled(&config) {
+        warn!("signed urls are disabled until signing_key is set");
+    }
+    let store: Box<dyn Store> = Box::new(
+        FileStore::new(&config.store)
+            .await
+            .expect("failed to create store directory. check config"),
+    );
 
     fs::create_dir_all(&config.temp_path)
         .await
         .expect("failed to create temp_path directories");
+    let cache = RenderCache::new(
+        &config.temp_path.join("render-cache"),
+        Duration::from_secs(config.expire_png_secs),
+        config.cache_max_bytes,
+        prometheus.registry(),
+    )
+    .expect("failed to open the render cache. check temp_path");
+    let threads = config.render_threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
+    let pool = RenderPool::new(threads, config.render_queue, prometheus.registry())
+        .expect("failed to start render threads");
+    let renderer = Renderer::new(
+        pool,
+        config.memory_render_max_bytes,
+        std::fs::canonicalize(&config.temp_path).expect("failed to find temp_path. check config"),
+        Duration::try_from_secs_f32(config.render_timeout_secs)
+            .expect("render_timeout_secs must be a positive number of seconds"),
+        prometheus.registry(),
+    )
+    .expect("failed to set up rendering");
+    let rates =
+        RateLimiter::new(&config, prometheus.registry()).expect("failed to set up rate limits");
     env::set_current_dir(config.temp_path).expect("failed to set PWD to temp_path. check config");
 
     rocket
-        .mount("/", routes![index::index, render_svg])
+        .mount(
+            "/",
+            routes![
+                index::index,
+                render_svg,
+                render_json,
+                list_fonts,
+                list_keys,
+                templates::create,
+                templates::list,
+                templates::get,
+                templates::update,
+                templates::delete,
+                templates::render_template,
+                templates::sign,
+            ],
+        )
         .mount("/metrics", prometheus.clone())
-        .register("/", catchers![internal_error, not_found, default])
+        .register(
+            "/",
+            catchers![
+                internal_error,
+                unauthorized,
+                forbidden,
+                too_many_requests,
+                not_found,
+                default
+            ],
+        )
+        .manage(keys)
+        .manage(rates)
+        .manage(fonts)
+        .manage(presets)
+        .manage(store)
+        .manage(cache)
+        .manage(renderer)
         .attach(prometheus)
+        .attach(RateLimitHeaders)
         .attach(instrumentation::TracingFairing)
         .attach(AdHoc::config::<AppConfig>())
 }
@@ -0,0 +1,119 @@
+/*! A fixed set of threads for CPU bound rendering, so parsing, rasterizing and
+encoding never run on the async executor and starve other requests.
+
+Work waits in a bounded queue for a free thread. Once the queue is full more
+work is refused with `503` and a `Retry-After`, rather than piling up.
+*/
+use crate::types::{ApiError, Result};
+
+use eyre::eyre;
+use rocket::http::Status;
+use rocket_prometheus::prometheus::{Gauge, IntGauge, Registry};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Queued {
+    job: Job,
+    since: Instant,
+}
+
+/// Render threads and the queue in front of them
+pub struct RenderPool {
+    queue: SyncSender<Queued>,
+    depth: IntGauge,
+    wait: Gauge,
+}
+
+impl RenderPool {
+    /// Start `threads` render threads, with room for `queue` jobs waiting on them,
+    /// and register the queue's metrics with `registry`.
+    pub fn new(threads: usize, queue: usize, registry: &Registry) -> Result<Self> {
+        let depth = IntGauge::new(
+            "social_image_render_queue_depth",
+            "Renders waiting for a render thread",
+        )?;
+        let wait = Gauge::new(
+            "social_image_render_queue_wait_seconds",
+            "How long the latest render waited for a render thread",
+        )?;
+        registry.register(Box::new(depth.clone()))?;
+        registry.register(Box::new(wait.clone()))?;
+
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for n in 0..threads.max(1) {
+            let (receiver, depth, wait) = (receiver.clone(), depth.clone(), wait.clone());
+            thread::Builder::new()
+                .name(format!("render-{n}"))
+                .spawn(move || work(&receiver, &depth, &wait))?;
+        }
+        Ok(RenderPool {
+            queue: sender,
+            depth,
+            wait,
+        })
+    }
+
+    /// Run `work` on a render thread once one is free.
+    /// If the caller gives up first, work still in the queue is skipped.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        work: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let (done, result) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            if done.is_closed() {
+                return;
+            }
+            let _ = done.send(work());
+        });
+
+        self.depth.inc();
+        let queued = Queued {
+            job,
+            since: Instant::now(),
+        };
+        if let Err(err) = self.queue.try_send(queued) {
+            self.depth.dec();
+            return Err(match err {
+                TrySendError::Full(_) => self.busy().into(),
+                TrySendError::Disconnected(_) => eyre!("No render threads are running"),
+            });
+        }
+        result.await.map_err(|_| eyre!("Render panicked"))?
+    }
+
+    /// The error for a full queue, asking to retry once about as long as the last wait has passed
+    fn busy(&self) -> ApiError {
+        let retry_after = self.wait.get().ceil().max(1.0) as u64;
+        ApiError::new(
+            Status::ServiceUnavailable,
+            "busy",
+            "every render thread is busy and the queue is full",
+        )
+        .header("Retry-After", retry_after)
+    }
+}
+
+/// A render thread: take jobs until the pool is dropped
+fn work(receiver: &Mutex<Receiver<Queued>>, depth: &IntGauge, wait: &Gauge) {
+    loop {
+        let next = receiver
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .recv();
+        let Ok(Queued { job, since }) = next else {
+            return;
+        };
+        depth.dec();
+        wait.set(since.elapsed().as_secs_f64());
+        // a panic drops the job's sender, which its caller sees as a failed render
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
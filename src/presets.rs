@@ -0,0 +1,85 @@
+/*! Named output presets for the platforms we share images on.
+
+A few are built in; more can be added (or the built in ones replaced) under
+`presets` in `App.toml`. `fit` is `cover` unless set:
+
+```toml
+[default.presets.mastodon]
+width = 1200
+height = 630
+format = "png"
+max_bytes = 8000000
+```
+*/
+use crate::types::{FitMode, OutputFormat};
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The output a platform expects
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Preset {
+    pub width: u32,
+    pub height: u32,
+
+    /// Cover unless configured otherwise, like the built in presets
+    #[serde(default = "Preset::default_fit")]
+    pub fit: FitMode,
+
+    /// Format to use unless the request asks for one
+    pub format: Option<OutputFormat>,
+
+    /// Largest file the platform accepts, in bytes
+    pub max_bytes: Option<usize>,
+}
+
+impl Preset {
+    const fn default_fit() -> FitMode {
+        FitMode::Cover
+    }
+
+    const fn new(width: u32, height: u32, format: Option<OutputFormat>, max_bytes: usize) -> Self {
+        Preset {
+            width,
+            height,
+            fit: Self::default_fit(),
+            format,
+            max_bytes: Some(max_bytes),
+        }
+    }
+}
+
+const MB: usize = 1024 * 1024;
+
+/// Presets available without any configuration
+fn builtin() -> [(&'static str, Preset); 5] {
+    [
+        ("og", Preset::new(1200, 630, None, 8 * MB)),
+        ("twitter", Preset::new(1200, 675, None, 5 * MB)),
+        ("twitter-summary", Preset::new(800, 800, None, 5 * MB)),
+        ("linkedin", Preset::new(1200, 627, None, 5 * MB)),
+        (
+            "instagram-square",
+            Preset::new(1080, 1080, Some(OutputFormat::Jpeg), 8 * MB),
+        ),
+    ]
+}
+
+/// Every preset a request can name
+pub struct Presets(HashMap<String, Preset>);
+
+impl Presets {
+    /// The built in presets, with `configured` ones added or replacing them.
+    pub fn new(configured: &HashMap<String, Preset>) -> Self {
+        let mut presets: HashMap<String, Preset> = builtin()
+            .into_iter()
+            .map(|(name, preset)| (name.to_string(), preset))
+            .collect();
+        presets.extend(configured.clone());
+        Presets(presets)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.0.get(name)
+    }
+}
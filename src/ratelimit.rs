@@ -0,0 +1,396 @@
+/*! Rate limits and render quotas, so one busy client can't keep the render
+threads from everyone else.
+
+Each api key has a token bucket, refilled at `rate_per_sec` up to `rate_burst`,
+and every request with the key takes a token. Signed urls also get a bucket per
+client IP from `signed_rate_per_sec` and `signed_rate_burst`, and take from the
+bucket of the key that signed them once their IP's allows it. The IP is the
+connection's, unless `ip_header` is set to the header a trusted proxy puts the
+client's in.
+A request finding its bucket empty is answered `429` with `Retry-After`, and
+every response to a limited client carries `RateLimit-Limit`,
+`RateLimit-Remaining` and `RateLimit-Reset`.
+
+Renders are also counted per key against `daily_quota` and `monthly_quota`,
+which start over at midnight UTC and on the first of the month. Renders that
+fail, over a limit, timed out or with the render threads busy, are given back.
+Buckets and
+counts are kept in memory, so they start over when the server does.
+*/
+use crate::apikey::KeyLimits;
+use crate::types::{ApiError, Result};
+use crate::AppConfig;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::serde::json::json;
+use rocket::{Request, Response};
+use rocket_prometheus::prometheus::{IntCounter, IntCounterVec, IntGauge, Opts, Registry};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::result;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use time::{Date, Month, OffsetDateTime};
+
+/// Most buckets kept. Past this full ones are dropped, as they'd be made anew
+/// the same, and then the one closest to full, so memory stays bounded however
+/// many clients there are.
+pub const MAX_BUCKETS: usize = 10_000;
+
+/// A bucket's refill rate and size. Zero `per_sec` is no limit.
+#[derive(Clone, Copy, Debug)]
+struct Rate {
+    per_sec: f64,
+    burst: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    Key(String),
+    Ip(IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When it will have refilled completely
+    full_at: Instant,
+}
+
+/// Where a request left its client's bucket, for the response headers
+#[derive(Clone, Copy, Debug)]
+struct Standing {
+    limit: u32,
+    remaining: u32,
+    /// Seconds until the bucket is full again
+    reset: u64,
+    /// Seconds until a token is available, when there was none for this request
+    retry_after: Option<u64>,
+}
+
+/// The request's [`Standing`], taken once however many guards ask
+struct Limited(Option<Standing>);
+
+/// Renders made with a key in the current UTC day and month
+struct Usage {
+    day: Date,
+    daily: u64,
+    monthly: u64,
+}
+
+impl Usage {
+    /// Start the counts over if `now` is on another day or month.
+    fn roll(&mut self, now: OffsetDateTime) {
+        let today = now.date();
+        if today == self.day {
+            return;
+        }
+        if (today.year(), today.month()) != (self.day.year(), self.day.month()) {
+            self.monthly = 0;
+        }
+        self.daily = 0;
+        self.day = today;
+    }
+}
+
+/// Every client's bucket and every key's render counts
+pub struct RateLimiter {
+    key_rate: Rate,
+    signed_rate: Rate,
+    daily_quota: Option<u64>,
+    monthly_quota: Option<u64>,
+    buckets: Mutex<HashMap<Client, Bucket>>,
+    usage: Mutex<HashMap<String, Usage>>,
+    requests: IntCounterVec,
+    renders: IntCounterVec,
+    limited: IntCounterVec,
+    signed_limited: IntCounter,
+    clients: IntGauge,
+}
+
+impl RateLimiter {
+    /// Limits from the server's config, registering per key usage metrics with `registry`.
+    pub fn new(config: &AppConfig, registry: &Registry) -> Result<Self> {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "social_image_key_requests_total",
+                "Requests made with each api key",
+            ),
+            &["key"],
+        )?;
+        let renders = IntCounterVec::new(
+            Opts::new(
+                "social_image_key_renders_total",
+                "Renders counted against each api key's quotas",
+            ),
+            &["key"],
+        )?;
+        let limited = IntCounterVec::new(
+            Opts::new(
+                "social_image_key_limited_total",
+                "Requests refused with 429 for each api key, by the limit they hit",
+            ),
+            &["key", "limit"],
+        )?;
+        let signed_limited = IntCounter::new(
+            "social_image_signed_limited_total",
+            "Signed url requests refused with 429 for their client's rate",
+        )?;
+        let clients = IntGauge::new(
+            "social_image_rate_buckets",
+            "Keys and client IPs with a rate limit bucket in memory",
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(renders.clone()))?;
+        registry.register(Box::new(limited.clone()))?;
+        registry.register(Box::new(signed_limited.clone()))?;
+        registry.register(Box::new(clients.clone()))?;
+        Ok(RateLimiter {
+            key_rate: Rate {
+                per_sec: config.rate_per_sec,
+                burst: config.rate_burst,
+            },
+            signed_rate: Rate {
+                per_sec: config.signed_rate_per_sec,
+                burst: config.signed_rate_burst,
+            },
+            daily_quota: config.daily_quota,
+            monthly_quota: config.monthly_quota,
+            buckets: Mutex::new(HashMap::new()),
+            usage: Mutex::new(HashMap::new()),
+            requests,
+            renders,
+            limited,
+            signed_limited,
+            clients,
+        })
+    }
+
+    /// The rate of a key with `limits`
+    fn key_rate(&self, limits: &KeyLimits) -> Rate {
+        Rate {
+            per_sec: limits.rate_per_sec.unwrap_or(self.key_rate.per_sec),
+            burst: limits.rate_burst.unwrap_or(self.key_rate.burst),
+        }
+    }
+
+    /// Take a token for a request with the key `name`, false when there is none.
+    pub fn key_request(&self, req: &Request<'_>, name: &str, limits: &KeyLimits) -> bool {
+        let client = Client::Key(name.to_string());
+        let Limited(standing) =
+            req.local_cache(|| Limited(self.take_token(client, self.key_rate(limits))));
+        let allowed = allowed(*standing);
+        self.requests.with_label_values(&[name]).inc();
+        if !allowed {
+            self.limited.with_label_values(&[name, "rate"]).inc();
+        }
+        allowed
+    }
+
+    /// Take a token for a signed url request from its client's IP, then from
+    /// the key `name` that signed it, false when either has none.
+    pub fn signed_request(&self, req: &Request<'_>, name: &str, limits: &KeyLimits) -> bool {
+        let Limited(standing) = req.local_cache(|| {
+            // the connection's address, as rocket only reads `ip_header` once configured
+            let by_ip = req
+                .client_ip()
+                .and_then(|ip| self.take_token(Client::Ip(ip), self.signed_rate));
+            if !allowed(by_ip) {
+                self.signed_limited.inc();
+                return Limited(by_ip);
+            }
+            let client = Client::Key(name.to_string());
+            let by_key = self.take_token(client, self.key_rate(limits));
+            self.requests.with_label_values(&[name]).inc();
+            if !allowed(by_key) {
+                self.limited.with_label_values(&[name, "rate"]).inc();
+            }
+            Limited(tighter(by_ip, by_key))
+        });
+        allowed(*standing)
+    }
+
+    fn take_token(&self, client: Client, rate: Rate) -> Option<Standing> {
+        if rate.per_sec <= 0.0 {
+            return None;
+        }
+        let burst = rate.burst.max(1) as f64;
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+            let fullest = (buckets.len() >= MAX_BUCKETS)
+                .then(|| buckets.iter().min_by_key(|(_, bucket)| bucket.full_at))
+                .flatten()
+                .map(|(client, _)| client.clone());
+            if let Some(fullest) = fullest {
+                buckets.remove(&fullest);
+            }
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            full_at: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * rate.per_sec;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated = now;
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(seconds((1.0 - bucket.tokens) / rate.per_sec).max(1))
+        };
+        let until_full = (burst - bucket.tokens) / rate.per_sec;
+        bucket.full_at = now + Duration::from_secs_f64(until_full);
+        let standing = Standing {
+            limit: burst as u32,
+            remaining: bucket.tokens as u32,
+            reset: seconds(until_full),
+            retry_after,
+        };
+        self.clients.set(buckets.len() as i64);
+        Some(standing)
+    }
+
+    /// Count a render against the key `name`'s daily and monthly quotas,
+    /// or refuse it once either is used up. It is [`settle`](Self::settle)d once done.
+    pub fn render(&self, name: &str, limits: &KeyLimits) -> result::Result<(), ApiError> {
+        let now = OffsetDateTime::now_utc();
+        let mut usage = self
+            .usage
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let usage = usage.entry(name.to_string()).or_insert(Usage {
+            day: now.date(),
+            daily: 0,
+            monthly: 0,
+        });
+        usage.roll(now);
+        let quotas = [
+            (
+                "daily_quota",
+                limits.daily_quota.or(self.daily_quota),
+                usage.daily,
+                next_day(now),
+            ),
+            (
+                "monthly_quota",
+                limits.monthly_quota.or(self.monthly_quota),
+                usage.monthly,
+                next_month(now),
+            ),
+        ];
+        for (limit, max, used, resets) in quotas {
+            let Some(max) = max else { continue };
+            if used >= max {
+                self.limited.with_label_values(&[name, limit]).inc();
+                return Err(ApiError::new(
+                    Status::TooManyRequests,
+                    "quota_exceeded",
+                    format!("the key's {limit} of {max} renders is used up"),
+                )
+                .with("limit", json!(limit))
+                .with("max", json!(max))
+                .header("Retry-After", (resets - now).whole_seconds().max(1)));
+            }
+        }
+        usage.daily += 1;
+        usage.monthly += 1;
+        Ok(())
+    }
+
+    /// Settle a render counted by [`render`](Self::render): `kept` against the
+    /// key's quotas once delivered, or given back when it failed.
+    pub fn settle(&self, name: &str, kept: bool) {
+        if kept {
+            self.renders.with_label_values(&[name]).inc();
+            return;
+        }
+        let mut usage = self
+            .usage
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(usage) = usage.get_mut(name) {
+            usage.daily = usage.daily.saturating_sub(1);
+            usage.monthly = usage.monthly.saturating_sub(1);
+        }
+    }
+}
+
+/// Whether a request that left a bucket at `standing` may go ahead
+fn allowed(standing: Option<Standing>) -> bool {
+    standing.is_none_or(|standing| standing.retry_after.is_none())
+}
+
+/// Of a request's two buckets, the refusing one, or else the one with fewer tokens left
+fn tighter(a: Option<Standing>, b: Option<Standing>) -> Option<Standing> {
+    match (a, b) {
+        (Some(a), Some(b))
+            if allowed(Some(a)) && (!allowed(Some(b)) || b.remaining < a.remaining) =>
+        {
+            Some(b)
+        }
+        (a, b) => a.or(b),
+    }
+}
+
+fn seconds(secs: f64) -> u64 {
+    secs.ceil() as u64
+}
+
+fn next_day(now: OffsetDateTime) -> OffsetDateTime {
+    now.date()
+        .next_day()
+        .map_or(now, |day| day.midnight().assume_utc())
+}
+
+fn next_month(now: OffsetDateTime) -> OffsetDateTime {
+    let (year, month) = match now.month() {
+        Month::December => (now.year() + 1, Month::January),
+        month => (now.year(), month.next()),
+    };
+    Date::from_calendar_date(year, month, 1).map_or(now, |day| day.midnight().assume_utc())
+}
+
+/// The error for a signed url request over its client's or its signer's rate
+pub fn too_many_requests() -> ApiError {
+    ApiError::new(
+        Status::TooManyRequests,
+        "rate_limited",
+        "too many requests with this signed url",
+    )
+}
+
+/// Adds the `RateLimit-*` headers, and `Retry-After` to refusals, for requests
+/// that took from a bucket
+pub struct RateLimitHeaders;
+
+#[rocket::async_trait]
+impl Fairing for RateLimitHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Limited(Some(standing)) = req.local_cache(|| Limited(None)) else {
+            return;
+        };
+        res.set_header(Header::new("RateLimit-Limit", standing.limit.to_string()));
+        res.set_header(Header::new(
+            "RateLimit-Remaining",
+            standing.remaining.to_string(),
+        ));
+        res.set_header(Header::new("RateLimit-Reset", standing.reset.to_string()));
+        if let Some(retry_after) = standing.retry_after {
+            res.set_header(Header::new("Retry-After", retry_after.to_string()));
+        }
+    }
+}
@@ -1,49 +1,345 @@
-use crate::types::{RenderSpace, Result, SvgDescription};
+use crate::encode;
+use crate::fonts::{self, FontLibrary, FontLog};
+use crate::pool::RenderPool;
+use crate::types::{
+    resource_name, ApiError, Limits, RenderSpace, Rendered, Result, SvgPackage, Target,
+};
+use crate::{template, text};
 
-use eyre::eyre;
-use std::{env, path};
-use tiny_skia::{Pixmap, Transform};
+use eyre::{eyre, Report};
+use flate2::read::GzDecoder;
+use rocket::http::Status;
+use rocket::serde::json::json;
+use rocket_prometheus::prometheus::{IntCounter, Registry};
+use std::future::Future;
+use std::io::Read;
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, fmt, path, sync::Arc};
+use tiny_skia::Pixmap;
 use tokio::fs;
-use usvg::{FitTo, Options, Size, Tree};
+use usvg::{fontdb, ImageHrefResolver, Options, Size, Tree};
 
-/// Given a full svg description, produce an encoded png
-pub async fn png_from_svg(mut contents: SvgDescription<'_>) -> Result<Vec<u8>> {
-    let space = RenderSpace::new(env::current_dir()?)?;
+/// Runs renders on the render pool, within the configured time budget
+pub struct Renderer {
+    pool: RenderPool,
+    /// Packages up to this size are rendered without writing their resources to disk
+    memory_max_bytes: u64,
+    /// Where larger packages' resources are written, each in its own [`RenderSpace`]
+    temp_path: path::PathBuf,
+    /// Longest a whole request's rendering may take, or zero for no limit
+    timeout: Duration,
+    timeouts: IntCounter,
+}
+
+impl Renderer {
+    /// Set up rendering on `pool`, registering its metrics with `registry`.
+    pub fn new(
+        pool: RenderPool,
+        memory_max_bytes: u64,
+        temp_path: path::PathBuf,
+        timeout: Duration,
+        registry: &Registry,
+    ) -> Result<Self> {
+        let timeouts = IntCounter::new(
+            "social_image_render_timeouts_total",
+            "Renders abandoned for taking longer than render_timeout_secs",
+        )?;
+        registry.register(Box::new(timeouts.clone()))?;
+        Ok(Renderer {
+            pool,
+            memory_max_bytes,
+            temp_path,
+            timeout,
+            timeouts,
+        })
+    }
+
+    /// Run `render`, giving up on it once it takes longer than the time budget.
+    /// Dropping it cleans up its render space and skips its work still queued
+    /// for the pool. Work already on a render thread is handed the [`Deadline`]
+    /// passed to `render`, and stops at its next step once it's out of time.
+    pub async fn timed<T, F>(&self, render: impl FnOnce(Deadline) -> F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        if self.timeout.is_zero() {
+            return render(Deadline::default()).await;
+        }
+        let deadline = Deadline::after(self.timeout);
+        match tokio::time::timeout(self.timeout, render(deadline)).await {
+            Ok(Err(err)) if err.is::<OutOfTime>() => Err(self.timed_out()),
+            Ok(result) => result,
+            Err(_) => Err(self.timed_out()),
+        }
+    }
+
+    fn timed_out(&self) -> Report {
+        self.timeouts.inc();
+        warn!("Render abandoned after {:?}", self.timeout);
+        ApiError::new(
+            Status::GatewayTimeout,
+            "render_timeout",
+            format!(
+                "rendering took longer than {} seconds",
+                self.timeout.as_secs_f32()
+            ),
+        )
+        .with("limit", json!("render_timeout_secs"))
+        .with("max", json!(self.timeout.as_secs_f32()))
+        .into()
+    }
+
+    /// Run CPU bound work on the render pool, so the executor can serve other requests.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        work: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        self.pool.run(work).await
+    }
+}
+
+/// When a request's rendering is out of time. Render threads can't be
+/// interrupted, so they check it between steps instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Deadline(Some(Instant::now() + timeout))
+    }
+
+    /// Fail once the deadline has passed, so no more work is started.
+    pub fn check(self) -> Result<()> {
+        match self.0 {
+            Some(at) if Instant::now() >= at => Err(OutOfTime.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Work stopped at its [`Deadline`], answered as any other timeout
+#[derive(Debug)]
+struct OutOfTime;
+
+impl fmt::Display for OutOfTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("render stopped at its deadline")
+    }
+}
+
+impl std::error::Error for OutOfTime {}
+
+/// An svg parsed with its resources, ready to render any number of times
+pub struct Parsed {
+    pub tree: Arc<Tree>,
+    pub fonts: FontLog,
+    limits: Limits,
+    deadline: Deadline,
+    /// Where resources were written, unless they were resolved from memory
+    _space: Option<RenderSpace>,
+}
 
-    // Lay out svg resources for rendering purposes
-    for (name, mut contents) in contents.resources {
-        let res_path = space.as_ref().join(name);
-        contents.persist_to(res_path).await?;
+/// Lay out an svg's resources, fill its variables and parse it.
+/// Small packages keep their resources in memory, larger ones are written to a [`RenderSpace`].
+pub async fn parse_svg(
+    package: &SvgPackage,
+    vars: &HashMap<String, String>,
+    library: &FontLibrary,
+    limits: &Limits,
+    renderer: &Renderer,
+    deadline: Deadline,
+) -> Result<Parsed> {
+    let mut db = library.database();
+    // Fonts are only loaded into this render's copy of the database, never shared with other requests.
+    for (name, data) in &package.resources {
+        if fonts::is_font(data) {
+            fonts::load_font(Arc::make_mut(&mut db), name, data.clone());
+        }
     }
 
+    let space = if package.size() <= renderer.memory_max_bytes {
+        None
+    } else {
+        let space = RenderSpace::new(&renderer.temp_path)?;
+        for (name, data) in &package.resources {
+            let res_path = space.as_ref().join(resource_name(name)?);
+            if let Some(parent) = res_path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::write(&res_path, data).await?;
+        }
+        Some(space)
+    };
+    // either way hrefs only reach the package's own resources, never other files
+    let image_href_resolver = match &space {
+        None => memory_resolver(package)?,
+        Some(space) => disk_resolver(space.as_ref().to_path_buf()),
+    };
+
+    let font_log = FontLog::default();
     let mut opt = Options {
-        resources_dir: Some(path::PathBuf::from(space.as_ref())),
+        font_family: db.family_name(&fontdb::Family::Serif).into(),
+        font_resolver: font_log.resolver(),
+        fontdb: db,
+        image_href_resolver,
         ..Options::default()
     };
 
-    if let Some(size) = Size::new(1080f64, 566f64) {
+    if let Some(size) = Size::from_wh(1080f32, 566f32) {
         opt.default_size = size;
     }
 
-    let svg_path = space.as_ref().join("main.svg");
-    contents.svg.persist_to(&svg_path).await?;
-    let svg_contents = fs::read(&svg_path).await?;
-    let rtree = Tree::from_data(&svg_contents, &opt)?;
-    let pixmap_size = rtree.size.to_screen_size();
-
-    match Pixmap::new(pixmap_size.width(), pixmap_size.height()) {
-        None => Err(eyre!("Failed to allocate a pixmap")),
-        Some(mut pixmap) => {
-            resvg::render(
-                &rtree,
-                FitTo::Original,
-                Transform::default(),
-                pixmap.as_mut(),
-            )
-            .ok_or(eyre!("failed to render"))?;
-
-            let encoded = pixmap.encode_png()?;
-            Ok(encoded)
-        }
+    let (svg, vars, limits) = (package.svg.clone(), vars.clone(), *limits);
+    let tree = renderer
+        .blocking(move || {
+            deadline.check()?;
+            // compressed or otherwise non utf-8 svgs can't hold placeholders or fitted text
+            match std::str::from_utf8(&svg) {
+                Ok(text) => {
+                    let text = template::substitute(text, &vars)?;
+                    check_nodes(&text, &limits)?;
+                    let text = text::fit(&text, &opt.fontdb, deadline)?.unwrap_or(text);
+                    deadline.check()?;
+                    Ok(Tree::from_data(text.as_bytes(), &opt)?)
+                }
+                Err(_) => {
+                    let data = decompress(&svg, &limits)?;
+                    deadline.check()?;
+                    check_nodes(std::str::from_utf8(&data)?, &limits)?;
+                    Ok(Tree::from_data(&data, &opt)?)
+                }
+            }
+        })
+        .await?;
+    Ok(Parsed {
+        tree: Arc::new(tree),
+        fonts: font_log,
+        limits,
+        deadline,
+        _space: space,
+    })
+}
+
+/// Resolve `<image>` hrefs to the package's resources without touching the disk.
+/// Anything that isn't one of its resources, like an absolute path, resolves to nothing.
+fn memory_resolver(package: &SvgPackage) -> Result<ImageHrefResolver<'static>> {
+    let resources = package
+        .resources
+        .iter()
+        .map(|(name, data)| Ok((resource_name(name)?, Arc::new(data.clone()))))
+        .collect::<Result<HashMap<_, _>>>()?;
+    // detects the image type from its data, as the default resolver does for files
+    let detect = ImageHrefResolver::default_data_resolver();
+    Ok(ImageHrefResolver {
+        resolve_data: ImageHrefResolver::default_data_resolver(),
+        resolve_string: Box::new(move |href, opts| {
+            let Some(data) = resource_name(href).ok().and_then(|n| resources.get(&n)) else {
+                warn!("'{href}' is not a resource. Skipped.");
+                return None;
+            };
+            detect("text/plain", data.clone(), opts)
+        }),
+    })
+}
+
+/// Resolve `<image>` hrefs to the package's resources written under `dir`.
+/// Names are checked as uploads are, so nothing outside `dir` is read.
+fn disk_resolver(dir: path::PathBuf) -> ImageHrefResolver<'static> {
+    let detect = ImageHrefResolver::default_data_resolver();
+    ImageHrefResolver {
+        resolve_data: ImageHrefResolver::default_data_resolver(),
+        resolve_string: Box::new(move |href, opts| {
+            let data = resource_name(href)
+                .ok()
+                .and_then(|name| std::fs::read(dir.join(name)).ok());
+            let Some(data) = data else {
+                warn!("'{href}' is not a resource. Skipped.");
+                return None;
+            };
+            detect("text/plain", Arc::new(data), opts)
+        }),
     }
 }
+
+/// Decompress an svgz, refusing one that expands past `max_request_bytes`
+/// before more than that is held in memory.
+fn decompress(svgz: &[u8], limits: &Limits) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    GzDecoder::new(svgz)
+        .take(limits.max_request_bytes.saturating_add(1))
+        .read_to_end(&mut data)?;
+    if data.len() as u64 > limits.max_request_bytes {
+        return Err(limits.svg_too_large().into());
+    }
+    Ok(data)
+}
+
+/// Count the svg's nodes without building a tree of more than `max_svg_nodes`.
+fn check_nodes(text: &str, limits: &Limits) -> Result<()> {
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        nodes_limit: limits.max_svg_nodes,
+    };
+    match roxmltree::Document::parse_with_options(text, options) {
+        Err(roxmltree::Error::NodesLimitReached) => Err(limits.too_many_nodes().into()),
+        // anything else malformed is reported by usvg
+        _ => Ok(()),
+    }
+}
+
+/// Render a parsed svg to the encoded bytes `target` asks for, on the render pool.
+/// Nothing is started once the deadline it was parsed with has passed.
+pub async fn render(parsed: &Parsed, target: &Target, renderer: &Renderer) -> Result<Vec<u8>> {
+    parsed.deadline.check()?;
+    let (tree, target, limits) = (parsed.tree.clone(), target.clone(), parsed.limits);
+    let deadline = parsed.deadline;
+    renderer
+        .blocking(move || {
+            deadline.check()?;
+            render_tree(&tree, &target, &limits, deadline)
+        })
+        .await
+}
+
+fn render_tree(
+    tree: &Tree,
+    target: &Target,
+    limits: &Limits,
+    deadline: Deadline,
+) -> Result<Vec<u8>> {
+    let encoding = &target.encoding;
+    if encoding.format.is_vector() {
+        return encode::pdf(tree, &target.sizing, encoding);
+    }
+    let (pixmap_size, transform) = target
+        .sizing
+        .layout(tree.size())
+        .ok_or(eyre!("Invalid output size"))?;
+    limits.check_output(pixmap_size)?;
+    let mut pixmap = Pixmap::new(pixmap_size.width(), pixmap_size.height())
+        .ok_or(eyre!("Failed to allocate a pixmap"))?;
+    resvg::render(tree, transform, &mut pixmap.as_mut());
+    deadline.check()?;
+    encode::encode(pixmap, encoding)
+}
+
+/// Given an svg and its resources, produce an encoded image
+pub async fn image_from_svg(
+    package: &SvgPackage,
+    vars: &HashMap<String, String>,
+    target: &Target,
+    library: &FontLibrary,
+    limits: &Limits,
+    renderer: &Renderer,
+) -> Result<Rendered> {
+    renderer
+        .timed(|deadline| async move {
+            let parsed = parse_svg(package, vars, library, limits, renderer, deadline).await?;
+            Ok(Rendered {
+                content_type: target.encoding.format.content_type(),
+                data: render(&parsed, target, renderer).await?,
+                fonts: parsed.fonts.entries(),
+            })
+        })
+        .await
+}
@@ -0,0 +1,286 @@
+/*! Signed urls, so a render url can be made public without the api key.
+
+The signature is an HMAC-SHA256 over the url's path and its query parameters,
+sorted, including the optional `expires` unix time and the `signer`, the name
+of the key that signed it. It is sent as the `sig` query parameter. A signed
+url is held to its signer's limits, rate and quotas, and stops working when the
+signer expires or is removed.
+
+The secret is `signing_key`, or `key` when that isn't set and isn't a hash,
+which anyone with the config could sign with. Without either, urls can't be
+signed and signatures are refused.
+*/
+use crate::apikey::{self, ApiKey, Keys};
+use crate::ratelimit::{self, RateLimiter};
+use crate::types::ApiError;
+use crate::AppConfig;
+
+use hmac::{Hmac, Mac};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use sha2::Sha256;
+use time::OffsetDateTime;
+
+/// Query parameter holding the signature
+pub const SIGNATURE: &str = "sig";
+
+/// Query parameter holding the unix time after which the url stops working
+pub const EXPIRES: &str = "expires";
+
+/// Query parameter holding the name of the key that signed the url
+pub const SIGNER: &str = "signer";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone, Copy, Debug)]
+pub enum SignatureError {
+    Invalid,
+    Expired,
+    /// The key that signed the url is gone, expired or may no longer render
+    Revoked,
+    /// There is no secret to sign or verify with
+    Disabled,
+    /// The client's IP is over `signed_rate_per_sec`
+    RateLimited,
+    Failure,
+}
+
+impl SignatureError {
+    pub fn status(self) -> Status {
+        match self {
+            SignatureError::Invalid
+            | SignatureError::Expired
+            | SignatureError::Revoked
+            | SignatureError::Disabled => Status::Forbidden,
+            SignatureError::RateLimited => Status::TooManyRequests,
+            SignatureError::Failure => Status::InternalServerError,
+        }
+    }
+
+    /// The JSON body this rejection is answered with
+    pub fn to_api_error(self) -> ApiError {
+        let status = self.status();
+        match self {
+            SignatureError::Invalid => ApiError::new(
+                status,
+                "invalid_signature",
+                "the url's signature doesn't match it",
+            ),
+            SignatureError::Expired => {
+                ApiError::new(status, "expired_signature", "the signed url has expired")
+            }
+            SignatureError::Revoked => ApiError::new(
+                status,
+                "revoked_signature",
+                "the key that signed the url can no longer render",
+            ),
+            SignatureError::Disabled => ApiError::new(
+                status,
+                "signing_disabled",
+                "signed urls aren't accepted until signing_key is set",
+            ),
+            SignatureError::RateLimited => ratelimit::too_many_requests(),
+            SignatureError::Failure => ApiError::new(
+                status,
+                "signing_config_error",
+                "signed urls are not configured",
+            ),
+        }
+    }
+}
+
+/// A request whose url carries a valid signature.
+/// Forwards when there is no signature at all.
+pub struct SignedUrl<'r> {
+    /// The key that signed the url
+    pub signer: ApiKey<'r>,
+}
+
+/// Access to a route that accepts either the api key or a signed url
+pub enum RenderAccess<'r> {
+    Key(ApiKey<'r>),
+    Signed(SignedUrl<'r>),
+}
+
+impl<'r> RenderAccess<'r> {
+    /// The key the request is held to: its own, or the one that signed its url
+    pub fn key(&self) -> &ApiKey<'r> {
+        match self {
+            RenderAccess::Key(key) => key,
+            RenderAccess::Signed(signed) => &signed.signer,
+        }
+    }
+}
+
+/// The secret urls are signed with, if there is one
+fn secret(config: &AppConfig) -> Option<&str> {
+    config
+        .signing_key
+        .as_deref()
+        .or(config.key.as_deref().filter(|key| !apikey::is_hash(key)))
+        .filter(|secret| !secret.is_empty())
+}
+
+/// Whether urls can be signed with the server's config
+pub fn enabled(config: &AppConfig) -> bool {
+    secret(config).is_some()
+}
+
+/// What gets signed: the path, then each sorted `name=value` pair, percent encoded.
+fn payload<'a>(path: &str, params: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let mut pairs: Vec<String> = params
+        .into_iter()
+        .filter(|(name, _)| *name != SIGNATURE)
+        .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+        .collect();
+    pairs.sort();
+    format!("{path}?{}", pairs.join("&"))
+}
+
+fn mac(secret: &str, payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac takes any key size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Sign `path` with `params` and an optional expiry for the key `signer`,
+/// returning the full url to share.
+pub fn sign(
+    config: &AppConfig,
+    path: &str,
+    params: &[(&str, &str)],
+    signer: &str,
+    expires: Option<i64>,
+) -> Result<String, SignatureError> {
+    let secret = secret(config).ok_or(SignatureError::Disabled)?;
+    let expires = expires.map(|e| e.to_string());
+    let params: Vec<(&str, &str)> = params
+        .iter()
+        .copied()
+        .chain([(SIGNER, signer)])
+        .chain(expires.as_deref().map(|e| (EXPIRES, e)))
+        .collect();
+    let payload = payload(path, params.iter().copied());
+    let signature = bs58::encode(mac(secret, &payload).finalize().into_bytes()).into_string();
+    let mut url = payload;
+    if !url.ends_with('?') {
+        url.push('&');
+    }
+    url.push_str(&format!("{SIGNATURE}={signature}"));
+    Ok(url)
+}
+
+/// Check the signature on a url's path and params, and that it hasn't expired,
+/// returning the name of the key that signed it.
+pub fn verify<'a>(
+    config: &AppConfig,
+    path: &str,
+    params: impl IntoIterator<Item = (&'a str, &'a str)> + Clone,
+) -> Result<&'a str, SignatureError> {
+    let secret = secret(config).ok_or(SignatureError::Disabled)?;
+    let signature = params
+        .clone()
+        .into_iter()
+        .find(|(name, _)| *name == SIGNATURE)
+        .and_then(|(_, sig)| bs58::decode(sig).into_vec().ok())
+        .ok_or(SignatureError::Invalid)?;
+    mac(secret, &payload(path, params.clone()))
+        .verify_slice(&signature)
+        .map_err(|_| SignatureError::Invalid)?;
+
+    let param = |param| {
+        (params.clone().into_iter())
+            .find(|(name, _)| *name == param)
+            .map(|(_, value)| value)
+    };
+    let signer = param(SIGNER).ok_or(SignatureError::Invalid)?;
+    match param(EXPIRES).map(str::parse::<i64>) {
+        None => Ok(signer),
+        Some(Ok(e)) if e > OffsetDateTime::now_utc().unix_timestamp() => Ok(signer),
+        Some(Ok(_)) => Err(SignatureError::Expired),
+        Some(Err(_)) => Err(SignatureError::Invalid),
+    }
+}
+
+/// Percent encode everything but unreserved characters
+fn encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+/// Why the request's signed url was refused, kept for the catchers
+struct Rejection(Option<SignatureError>);
+
+/// The error for a request whose signed url was refused, if it was
+pub fn rejection(req: &Request<'_>) -> Option<ApiError> {
+    req.local_cache(|| Rejection(None))
+        .0
+        .map(SignatureError::to_api_error)
+}
+
+fn reject<T>(req: &Request<'_>, error: SignatureError) -> Outcome<T, SignatureError> {
+    req.local_cache(|| Rejection(Some(error)));
+    Outcome::Error((error.status(), error))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SignedUrl<'r> {
+    type Error = SignatureError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(query) = req.uri().query() else {
+            return Outcome::Forward(Status::Unauthorized);
+        };
+        if !query.segments().any(|(name, _)| name == SIGNATURE) {
+            return Outcome::Forward(Status::Unauthorized);
+        }
+        let Some(config) = req.rocket().state::<AppConfig>() else {
+            error!("Failed to get config");
+            return reject(req, SignatureError::Failure);
+        };
+        let signer = match verify(config, req.uri().path().as_str(), query.segments()) {
+            Ok(signer) => signer,
+            Err(err) => return reject(req, err),
+        };
+        let (Some(keys), Some(rates)) = (
+            req.rocket().state::<Keys>(),
+            req.rocket().state::<RateLimiter>(),
+        ) else {
+            error!("Failed to get keys or rate limiter");
+            return reject(req, SignatureError::Failure);
+        };
+        let Some(signer) = keys.named(signer) else {
+            info!("Refused url signed by {signer}");
+            return reject(req, SignatureError::Revoked);
+        };
+        if !signer.signed_request(req, rates) {
+            return reject(req, SignatureError::RateLimited);
+        }
+        Outcome::Success(SignedUrl { signer })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RenderAccess<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.guard::<SignedUrl>().await {
+            Outcome::Success(signed) => Outcome::Success(RenderAccess::Signed(signed)),
+            Outcome::Error((status, _)) => Outcome::Error((status, ())),
+            Outcome::Forward(_) => match req.guard::<ApiKey<'r>>().await {
+                Outcome::Success(key) => Outcome::Success(RenderAccess::Key(key)),
+                Outcome::Error((status, _)) => Outcome::Error((status, ())),
+                Outcome::Forward(status) => Outcome::Forward(status),
+            },
+        }
+    }
+}
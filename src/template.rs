@@ -0,0 +1,84 @@
+/*! Fill placeholders in an svg with request variables.
+
+A placeholder is `{{name}}`, or `{{name|default}}` to use `default` when the
+request has no `name`. Whitespace around the name and default is ignored.
+Values and defaults are plain text and are XML escaped before substitution,
+so they can't add markup to the svg.
+*/
+use crate::types::ApiError;
+
+use rocket::http::Status;
+use rocket::serde::json::json;
+use std::collections::{BTreeSet, HashMap};
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+/// Replace every placeholder in `svg` from `vars`.
+///
+/// Fails with a 422 listing every variable that has neither a value nor a default.
+pub fn substitute(svg: &str, vars: &HashMap<String, String>) -> Result<String, ApiError> {
+    let mut out = String::with_capacity(svg.len());
+    let mut missing = BTreeSet::new();
+    let mut rest = svg;
+    while let Some(start) = rest.find(OPEN) {
+        let Some(end) = rest[start + OPEN.len()..].find(CLOSE) else {
+            break;
+        };
+        let inner = &rest[start + OPEN.len()..start + OPEN.len() + end];
+        let (name, default) = match inner.split_once('|') {
+            Some((name, default)) => (name.trim(), Some(default.trim())),
+            None => (inner.trim(), None),
+        };
+        out.push_str(&rest[..start]);
+        if !is_name(name) {
+            // not ours, e.g. a literal `{{` in a style sheet
+            out.push_str(OPEN);
+            rest = &rest[start + OPEN.len()..];
+            continue;
+        }
+        match vars.get(name).map(String::as_str).or(default) {
+            Some(value) => out.push_str(&escape(value)),
+            None => {
+                missing.insert(name);
+            }
+        }
+        rest = &rest[start + OPEN.len() + end + CLOSE.len()..];
+    }
+    out.push_str(rest);
+
+    if missing.is_empty() {
+        Ok(out)
+    } else {
+        let missing: Vec<&str> = missing.into_iter().collect();
+        Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "missing_variables",
+            format!("no value for template variables: {}", missing.join(", ")),
+        )
+        .with("missing", json!(missing)))
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// `value` with the characters that are special in XML text and attributes escaped
+pub fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,228 @@
+/*! Routes to manage templates kept in the [`Store`], so an svg and its
+resources can be uploaded once and rendered many times. */
+use crate::apikey::{ApiKey, TemplatesScope};
+use crate::cache::{CacheKey, RenderCache};
+use crate::fonts::FontLibrary;
+use crate::ratelimit::RateLimiter;
+use crate::render::{self, Renderer};
+use crate::signed::{self, RenderAccess};
+use crate::types::{
+    ApiError, Cacheable, Conditions, Encoding, OutputFormat, Sizing, Store, SvgId, SvgInfo,
+    SvgUpload, Target,
+};
+use crate::AppConfig;
+
+use rocket::form::{self, Form};
+use rocket::http::Status;
+use rocket::response::status::{Created, NoContent};
+use rocket::serde::json::{json, Json, Value};
+use rocket::serde::Deserialize;
+use rocket::State;
+use std::collections::HashMap;
+use time::OffsetDateTime;
+
+type Result<T> = std::result::Result<T, ApiError>;
+
+#[post("/templates", format = "multipart/form-data", data = "<upload>")]
+pub async fn create(
+    upload: std::result::Result<Form<SvgUpload<'_>>, form::Errors<'_>>,
+    store: &State<Box<dyn Store>>,
+    config: &State<AppConfig>,
+    api_key: ApiKey<'_, TemplatesScope>,
+) -> Result<Created<Json<SvgInfo>>> {
+    let limits = api_key.limits(config.limits());
+    let upload = limits.form(upload)?;
+    let package = (upload.package(&limits).await).map_err(ApiError::from_report)?;
+    let id = store.save(&package).await.map_err(ApiError::from_report)?;
+    let info = store.info(&id).await.map_err(ApiError::from_report)?;
+    Ok(Created::new(format!("/templates/{id}")).body(Json(info)))
+}
+
+#[get("/templates")]
+pub async fn list(
+    store: &State<Box<dyn Store>>,
+    _api_key: ApiKey<'_, TemplatesScope>,
+) -> Result<Json<Vec<SvgInfo>>> {
+    let infos = store.list().await.map_err(ApiError::from_report)?;
+    Ok(Json(infos))
+}
+
+#[get("/templates/<id>")]
+pub async fn get(
+    id: &str,
+    store: &State<Box<dyn Store>>,
+    _api_key: ApiKey<'_, TemplatesScope>,
+) -> Result<Json<SvgInfo>> {
+    let info = store
+        .info(&parse_id(id)?)
+        .await
+        .map_err(ApiError::from_report)?;
+    Ok(Json(info))
+}
+
+#[put("/templates/<id>", format = "multipart/form-data", data = "<upload>")]
+pub async fn update(
+    id: &str,
+    upload: std::result::Result<Form<SvgUpload<'_>>, form::Errors<'_>>,
+    store: &State<Box<dyn Store>>,
+    config: &State<AppConfig>,
+    api_key: ApiKey<'_, TemplatesScope>,
+) -> Result<Json<SvgInfo>> {
+    let id = parse_id(id)?;
+    let limits = api_key.limits(config.limits());
+    let upload = limits.form(upload)?;
+    let package = (upload.package(&limits).await).map_err(ApiError::from_report)?;
+    store
+        .update(&id, &package)
+        .await
+        .map_err(ApiError::from_report)?;
+    let info = store.info(&id).await.map_err(ApiError::from_report)?;
+    Ok(Json(info))
+}
+
+#[delete("/templates/<id>")]
+pub async fn delete(
+    id: &str,
+    store: &State<Box<dyn Store>>,
+    _api_key: ApiKey<'_, TemplatesScope>,
+) -> Result<NoContent> {
+    store
+        .delete(&parse_id(id)?)
+        .await
+        .map_err(ApiError::from_report)?;
+    Ok(NoContent)
+}
+
+/// Render a stored template, filling its variables from the query string.
+/// Needs the api key, or a url signed by [`sign`].
+/// `file` is `render.png`, or `render.jpg`, `render.webp` or `render.pdf`.
+#[get("/templates/<id>/<file>?<vars..>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn render_template(
+    id: &str,
+    file: &str,
+    mut vars: HashMap<String, String>,
+    store: &State<Box<dyn Store>>,
+    fonts: &State<FontLibrary>,
+    cache: &State<RenderCache>,
+    renderer: &State<Renderer>,
+    config: &State<AppConfig>,
+    rates: &State<RateLimiter>,
+    conditions: Conditions,
+    access: RenderAccess<'_>,
+) -> Result<Cacheable> {
+    vars.remove(signed::SIGNATURE);
+    vars.remove(signed::SIGNER);
+    // a signed url shouldn't be kept past its expiry
+    let expires = vars
+        .remove(signed::EXPIRES)
+        .and_then(|e| e.parse::<i64>().ok());
+    let format = file
+        .strip_prefix("render.")
+        .and_then(OutputFormat::from_extension)
+        .ok_or_else(|| ApiError::new(Status::NotFound, "not_found", format!("no file {file}")))?;
+    let target = Target {
+        sizing: Sizing::default(),
+        encoding: Encoding {
+            format,
+            ..Encoding::default()
+        },
+    };
+    let limits = access.key().limits(config.limits());
+    let id = parse_id(id)?;
+    let info = store.info(&id).await.map_err(ApiError::from_report)?;
+    let package = store.load(&id).await.map_err(ApiError::from_report)?;
+    let key = CacheKey::new(&package, &vars, fonts, &limits, &target);
+    let private = matches!(access, RenderAccess::Key(_));
+    let mut validators = config.validators(&key, Some(info.updated), private);
+    if let Some(expires) = expires {
+        let left = expires - OffsetDateTime::now_utc().unix_timestamp();
+        validators.max_age = validators.max_age.min(left.max(0) as u64);
+    }
+    if conditions.not_modified(&validators) {
+        return Ok(Cacheable {
+            validators,
+            rendered: None,
+        });
+    }
+    let render = cache.get_or_render(
+        &key,
+        render::image_from_svg(&package, &vars, &target, fonts, &limits, renderer),
+    );
+    let rendered = access
+        .key()
+        .count_render(rates, async { render.await.map_err(ApiError::from_report) })
+        .await?;
+    Ok(Cacheable {
+        validators,
+        rendered: Some(rendered),
+    })
+}
+
+/// What to sign a render url for
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SignRequest {
+    /// PNG when missing
+    format: Option<OutputFormat>,
+
+    /// Template variables to put in the query string
+    #[serde(default)]
+    vars: HashMap<String, String>,
+
+    /// Seconds until the url stops working. Never when missing.
+    expires_in: Option<u64>,
+}
+
+/// Mint a signed render url for a template, to share where the api key can't go.
+/// Renders from it are held to the signing key's limits, rate and quotas.
+#[post("/templates/<id>/sign", format = "json", data = "<request>")]
+pub async fn sign(
+    id: &str,
+    request: Json<SignRequest>,
+    store: &State<Box<dyn Store>>,
+    config: &State<AppConfig>,
+    api_key: ApiKey<'_>,
+) -> Result<Json<Value>> {
+    let id = parse_id(id)?;
+    store.info(&id).await.map_err(ApiError::from_report)?;
+    if let Some(name) = [signed::SIGNATURE, signed::EXPIRES, signed::SIGNER]
+        .into_iter()
+        .find(|name| request.vars.contains_key(*name))
+    {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "reserved_variable",
+            format!("{name} can't be a variable in a signed url"),
+        ));
+    }
+
+    let expires = request.expires_in.map(|secs| {
+        OffsetDateTime::now_utc()
+            .unix_timestamp()
+            .saturating_add(secs.min(i64::MAX as u64) as i64)
+    });
+    let vars: Vec<(&str, &str)> = request
+        .vars
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    let path = format!(
+        "/templates/{id}/render.{}",
+        request.format.unwrap_or(OutputFormat::Png).extension()
+    );
+    let url = signed::sign(config, &path, &vars, api_key.name, expires).map_err(|_| {
+        ApiError::new(
+            Status::NotImplemented,
+            "signing_disabled",
+            "urls can't be signed until signing_key is set",
+        )
+    })?;
+    Ok(Json(json!({"url": url, "expires": expires})))
+}
+
+/// Anything that isn't an id can't name a template
+pub fn parse_id(id: &str) -> Result<SvgId> {
+    id.parse()
+        .map_err(|_| ApiError::new(Status::NotFound, "not_found", format!("no svg {id}")))
+}
@@ -1,10 +1,107 @@
-use super::rocket;
-use rocket::http::{ContentType, Status};
-use rocket::local::asynchronous::Client;
+use super::{rocket, AppConfig};
+use crate::cache::{CacheKey, RenderCache};
+use crate::encode;
+use crate::fonts::{self, FontLibrary, FontResolution};
+use crate::pool::RenderPool;
+use crate::presets::{Preset, Presets};
+use crate::ratelimit::{self, RateLimiter};
+use crate::render::{self, Deadline, Renderer};
+use crate::types::{
+    resource_name, ApiError, ArchiveFormat, Background, Encoding, FileStore, FitMode, Limits,
+    OutputFormat, RenderSpace, Rendered, Sizing, Store, SvgId, SvgPackage, Target,
+};
+use crate::{apikey, signed, template, text};
+use rocket::http::{Accept, ContentType, Header, MediaType, Method, Status};
+use rocket::local::asynchronous::{Client, LocalRequest};
+use rocket::serde::json::{json, Value};
+use rocket_prometheus::prometheus::Registry;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const BOUNDARY: &str = "X-SOCIAL-IMAGE-BOUNDARY";
+
+const SQUARE_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10">
+<rect width="20" height="10" fill="#ff0000"/>
+</svg>"##;
+
+/// Encode text `fields` and `(field, filename, contents)` files as a `multipart/form-data` body
+fn multipart(fields: &[(&str, &str)], files: &[(&str, &str, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (field, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{field}\"\r\n\r\n{value}\r\n"
+            )
+            .as_bytes(),
+        );
+    }
+    for (field, filename, contents) in files {
+        body.extend_from_slice(
+            format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{field}\"; filename=\"{filename}\"\r\n\
+                 Content-Type: application/octet-stream\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(contents);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+    body
+}
+
+fn multipart_type() -> ContentType {
+    ContentType::new("multipart", "form-data").with_params(("boundary", BOUNDARY))
+}
+
+/// An authenticated `POST /image` of `SQUARE_SVG` with extra form `fields`
+fn post_square<'c>(client: &'c Client, fields: &[(&str, &str)]) -> LocalRequest<'c> {
+    client
+        .post("/image")
+        .header(multipart_type())
+        .header(Header::new("x-api-key", "XO"))
+        .body(multipart(
+            fields,
+            &[("svg", "main.svg", SQUARE_SVG.as_bytes())],
+        ))
+}
+
+/// Width and height from a png's IHDR chunk
+fn png_size(png: &[u8]) -> (u32, u32) {
+    let be = |i: usize| u32::from_be_bytes(png[i..i + 4].try_into().unwrap());
+    (be(16), be(20))
+}
 
 #[async_test]
 async fn tests() {
     std::env::set_var("APP_KEY", "XO");
+    // start with an empty store and render cache
+    let scratch = std::env::temp_dir().join(format!("social-image-test-{}", SvgId::new()));
+    std::env::set_var("APP_TEMP_PATH", scratch.join("tmp"));
+    std::env::set_var("APP_STORE", scratch.join("store"));
+    // render through the disk, memory is covered by renders_from_memory
+    std::env::set_var("APP_MEMORY_RENDER_MAX_BYTES", "0");
+    std::env::set_var("APP_MAX_REQUEST_BYTES", "262144");
+    std::env::set_var("APP_MAX_RESOURCE_BYTES", "1024");
+    std::env::set_var("APP_MAX_RESOURCES", "4");
+    std::env::set_var("APP_MAX_WIDTH", "4000");
+    std::env::set_var("APP_MAX_PIXELS", "4000000");
+    std::env::set_var("APP_MAX_SVG_NODES", "500");
+    std::env::set_var(
+        "APP_KEYS",
+        concat!(
+            r#"{renderer={key="R",limits={max_width=10}},"#,
+            r#"old={key="O",scopes=["admin"],expires="2000-01-01T00:00:00Z"},"#,
+            // "N" by its hash, and "P" from before a rotation that has since ended
+            r#"rotated={key=["sha256:8ce86a6ae65d3692e7305e2c58ac62eebd97d3d943e093f577da25c36988246b","#,
+            r#"{key="P",expires="2000-01-01T00:00:00Z"}]},"#,
+            r#"limited={key="L",limits={rate_per_sec=0.001,rate_burst=2,daily_quota=1}},"#,
+            r#"quota={key="Q",limits={daily_quota=1}}}"#,
+        ),
+    );
+    // only requests with a remote address are limited, see signed_rate below
+    std::env::set_var("APP_SIGNED_RATE_PER_SEC", "0.001");
+    std::env::set_var("APP_SIGNED_RATE_BURST", "2");
     let client = Client::tracked(rocket().await)
         .await
         .expect("valid rocket instance");
@@ -14,27 +111,745 @@ async fn tests() {
         Status::NotFound
     );
 
+    let response = client
+        .post("/image")
+        .header(ContentType::new("multipart", "form-data"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(
+        response.headers().get_one("WWW-Authenticate"),
+        Some("Bearer realm=\"social-image\"")
+    );
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "missing_key");
+
     assert_eq!(
         client
             .post("/image")
             .header(ContentType::new("multipart", "form-data"))
+            .header(ContentType::new("x-api-key", "XO"))
             .dispatch()
             .await
             .status(),
-        Status::BadRequest
+        Status::Unauthorized
     );
 
+    let response = post_square(&client, &[]).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+    assert!(response.headers().get_one("ETag").is_some());
+    let cache_control = response.headers().get_one("Cache-Control").unwrap();
+    assert!(cache_control.starts_with("private, "));
+    let png = response.into_bytes().await.expect("body");
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(png_size(&png), (20, 10));
+
+    let response = post_square(&client, &[("width", "40"), ("zoom", "2")])
+        .dispatch()
+        .await;
+    let png = response.into_bytes().await.expect("body");
+    assert_eq!(png_size(&png), (80, 40));
+
+    let response = post_square(&client, &[])
+        .header(Accept::JPEG)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JPEG));
+    let jpeg = response.into_bytes().await.expect("body");
+    assert_eq!(&jpeg[..2], b"\xff\xd8");
+
+    let response = post_square(&client, &[("preset", "og")]).dispatch().await;
+    let png = response.into_bytes().await.expect("body");
+    assert_eq!(png_size(&png), (1200, 630));
+    // a width alone replaces the preset's size, keeping the svg's aspect ratio
+    let response = post_square(&client, &[("preset", "og"), ("width", "600")])
+        .dispatch()
+        .await;
+    let png = response.into_bytes().await.expect("body");
+    assert_eq!(png_size(&png), (600, 300));
+
+    let response = post_square(&client, &[("preset", "myspace")])
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "unknown_preset");
+
+    let response = post_square(&client, &[("quality", "0")]).dispatch().await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "invalid_form");
+    assert!(error["message"].as_str().unwrap().contains("quality"));
+
+    let response = post_square(&client, &[("format", "webp")]).dispatch().await;
+    assert_eq!(response.content_type(), Some(ContentType::WEBP));
+
+    let response = post_square(&client, &[])
+        .header(Accept::from(MediaType::PDF))
+        .dispatch()
+        .await;
+    assert_eq!(response.content_type(), Some(ContentType::PDF));
+    let pdf = response.into_bytes().await.expect("body");
+    assert!(pdf.starts_with(b"%PDF-"));
+    let has = |pdf: &[u8], needle: &[u8]| pdf.windows(needle.len()).any(|w| w == needle);
+    // 20x10 pixels prints as 15x7.5 points
+    assert!(has(&pdf, b"/MediaBox [0 0 15 7.5]"));
+
+    let response = post_square(
+        &client,
+        &[("format", "pdf"), ("width", "40"), ("background", "blue")],
+    )
+    .dispatch()
+    .await;
+    let pdf = response.into_bytes().await.expect("body");
+    assert!(has(&pdf, b"/MediaBox [0 0 30 15]"));
+    assert!(has(&pdf, b"0 0 1 rg"));
+
     assert_eq!(
-        client
-            .post("/image")
-            .header(ContentType::new("multipart", "form-data"))
-            .header(ContentType::new("x-api-key", "XO"))
+        post_square(&client, &[])
+            .header(Accept::HTML)
             .dispatch()
             .await
             .status(),
-        Status::BadRequest
+        Status::NotAcceptable
+    );
+
+    let variants = [
+        ("variants[0].preset", "og"),
+        ("variants[1].name", "small"),
+        ("variants[1].width", "40"),
+        ("variants[1].format", "webp"),
+    ];
+    let response = post_square(&client, &variants).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::ZIP));
+    let zip = response.into_bytes().await.expect("body");
+    assert!(zip.starts_with(b"PK"));
+    assert!(zip.windows(6).any(|w| w == b"og.png"));
+    assert!(zip.windows(10).any(|w| w == b"small.webp"));
+
+    let mut fields = variants.to_vec();
+    fields.push(("archive", "multipart"));
+    let response = post_square(&client, &fields).dispatch().await;
+    let content_type = response.content_type().expect("content type");
+    assert_eq!(content_type.top(), "multipart");
+    assert_eq!(content_type.sub(), "mixed");
+    let boundary = content_type
+        .param("boundary")
+        .expect("boundary")
+        .to_string();
+    let body = response.into_bytes().await.expect("body");
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.ends_with(&format!("--{boundary}--\r\n")));
+    assert!(body.contains("filename=\"og.png\""));
+    assert!(body.contains("Content-Type: image/webp"));
+
+    let response = post_square(&client, &[("variants[0].preset", "myspace")])
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    let template = br#"<svg xmlns="http://www.w3.org/2000/svg" width="{{width|20}}" height="10">
+<title>{{title}}</title></svg>"#;
+    let response = client
+        .post("/image")
+        .header(multipart_type())
+        .header(Header::new("x-api-key", "XO"))
+        .body(multipart(
+            &[("vars[title]", "Cats & <Dogs>"), ("vars[width]", "30")],
+            &[("svg", "main.svg", template)],
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let png = response.into_bytes().await.expect("body");
+    assert_eq!(png_size(&png), (30, 10));
+
+    let response = client
+        .post("/image")
+        .header(multipart_type())
+        .header(Header::new("x-api-key", "XO"))
+        .body(multipart(&[], &[("svg", "main.svg", template)]))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "missing_variables");
+    assert_eq!(error["missing"], json!(["title"]));
+
+    let upload = |method, uri: &str| {
+        client
+            .req(method, uri.to_string())
+            .header(multipart_type())
+            .header(Header::new("x-api-key", "XO"))
+    };
+    let response = upload(Method::Post, "/templates")
+        .body(multipart(
+            &[],
+            &[
+                ("svg", "main.svg", SQUARE_SVG.as_bytes()),
+                ("resources[img/logo.png]", "logo.png", b"png"),
+            ],
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Created);
+    let location = response
+        .headers()
+        .get_one("Location")
+        .expect("location")
+        .to_string();
+    let created: Value = response.into_json().await.expect("json");
+    let id = created["id"].as_str().expect("id").to_string();
+    assert_eq!(location, format!("/templates/{id}"));
+    assert_eq!(created["resources"], json!(["img/logo.png"]));
+    assert_eq!(created["size"], SQUARE_SVG.len() + 3);
+
+    let response = client
+        .get("/templates")
+        .header(Header::new("x-api-key", "XO"))
+        .dispatch()
+        .await;
+    let listed: Value = response.into_json().await.expect("json");
+    assert!(listed.as_array().expect("list").contains(&created));
+
+    let response = upload(Method::Put, &location)
+        .body(multipart(
+            &[],
+            &[("svg", "main.svg", SQUARE_SVG.as_bytes())],
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let updated: Value = response.into_json().await.expect("json");
+    assert_eq!(updated["created"], created["created"]);
+    assert_eq!(updated["resources"], json!([]));
+
+    let response = client
+        .get(location.clone())
+        .header(Header::new("x-api-key", "XO"))
+        .dispatch()
+        .await;
+    assert_eq!(response.into_json::<Value>().await, Some(updated));
+
+    let response = upload(Method::Put, &location)
+        .body(multipart(&[], &[("svg", "main.svg", template)]))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let get = |uri: String| client.get(uri).header(Header::new("x-api-key", "XO"));
+    let response = get(format!("{location}/render.png?title=Hi&width=40"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+    let header = |name| response.headers().get_one(name).map(str::to_string);
+    let etag = header("ETag").expect("etag");
+    let last_modified = header("Last-Modified").expect("last modified");
+    assert_eq!(
+        header("Cache-Control").as_deref(),
+        Some("private, max-age=86400")
+    );
+    assert!(header("Expires").expect("expires").ends_with(" GMT"));
+    let png = response.into_bytes().await.expect("body");
+    assert_eq!(png_size(&png), (40, 10));
+    let revalidate = |name: &'static str, value: &str| {
+        get(format!("{location}/render.png?title=Hi&width=40"))
+            .header(Header::new(name, value.to_string()))
+    };
+    let response = revalidate("If-None-Match", &etag).dispatch().await;
+    assert_eq!(response.status(), Status::NotModified);
+    assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+    assert!(response.into_bytes().await.unwrap_or_default().is_empty());
+    let other = revalidate("If-None-Match", "\"other\"").dispatch().await;
+    assert_eq!(other.status(), Status::Ok);
+    let response = revalidate("If-Modified-Since", &last_modified)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotModified);
+    let response = revalidate("If-Modified-Since", "Sat, 01 Jan 2000 00:00:00 GMT")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = get(format!("{location}/render.jpg?title=Hi"))
+        .dispatch()
+        .await;
+    assert_eq!(response.content_type(), Some(ContentType::JPEG));
+    let response = get(format!("{location}/render.png")).dispatch().await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let response = get(format!("{location}/render.gif")).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = client
+        .post(format!("{location}/sign"))
+        .header(ContentType::JSON)
+        .header(Header::new("x-api-key", "XO"))
+        .body(r#"{"vars": {"title": "Cats & dogs"}, "expires_in": 60}"#)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let signed: Value = response.into_json().await.expect("json");
+    let url = signed["url"].as_str().expect("url").to_string();
+    assert!(url.starts_with(&format!("{location}/render.png?")));
+    assert!(signed["expires"].is_i64());
+    let response = client.get(url.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+    let cache_control = response.headers().get_one("Cache-Control").unwrap();
+    assert!(cache_control.starts_with("public, "));
+    let tampered = url.replace("Cats", "Rats");
+    let response = client.get(tampered).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "invalid_signature");
+    let config = client.rocket().state::<AppConfig>().expect("config");
+    let path = format!("{location}/render.png");
+    let expired = signed::sign(config, &path, &[], "ci", Some(1)).unwrap();
+    let response = client.get(expired).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "expired_signature");
+    let revoked = signed::sign(config, &path, &[], "old", None).unwrap();
+    let response = client.get(revoked).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "revoked_signature");
+    // a url is held to the limits of the key that signed it
+    let response = client
+        .post(format!("{location}/sign"))
+        .header(ContentType::JSON)
+        .header(Header::new("x-api-key", "R"))
+        .body("{}")
+        .dispatch()
+        .await;
+    let narrow: Value = response.into_json().await.expect("json");
+    let narrow = narrow["url"].as_str().expect("url").to_string();
+    let response = client.get(narrow).dispatch().await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    // signed_rate: each address gets its own bucket of 2
+    let from = |ip: &str| {
+        client
+            .get(url.clone())
+            .remote(format!("{ip}:4000").parse().expect("address"))
+    };
+    let response = from("192.0.2.1").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("RateLimit-Limit"), Some("2"));
+    assert_eq!(response.headers().get_one("RateLimit-Remaining"), Some("1"));
+    assert_eq!(from("192.0.2.1").dispatch().await.status(), Status::Ok);
+    let response = from("192.0.2.1").dispatch().await;
+    assert_eq!(response.status(), Status::TooManyRequests);
+    assert!(response.headers().get_one("Retry-After").is_some());
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "rate_limited");
+    // the address can't be claimed in a header unless ip_header is configured
+    let claimed = from("192.0.2.1").header(Header::new("X-Real-IP", "192.0.2.3"));
+    assert_eq!(claimed.dispatch().await.status(), Status::TooManyRequests);
+    assert_eq!(from("192.0.2.2").dispatch().await.status(), Status::Ok);
+    let response = client
+        .get(format!("{location}/render.png?title=Hi"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let delete = || {
+        client
+            .delete(location.clone())
+            .header(Header::new("x-api-key", "XO"))
+    };
+    assert_eq!(delete().dispatch().await.status(), Status::NoContent);
+    assert_eq!(delete().dispatch().await.status(), Status::NotFound);
+    let response = client
+        .get("/templates/not..an-id")
+        .header(Header::new("x-api-key", "XO"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(
+        client.get("/templates").dispatch().await.status(),
+        Status::Unauthorized
     );
 
+    let metrics = client.get("/metrics").dispatch().await;
+    let metrics = metrics.into_string().await.expect("metrics");
+    assert!(metrics.contains("social_image_render_cache_hits_total"));
+    assert!(metrics.contains("social_image_render_cache_misses_total"));
+
+    let mut dot = tiny_skia::Pixmap::new(1, 1).unwrap();
+    dot.fill(tiny_skia::Color::from_rgba8(0, 0, 255, 255));
+    let dot = dot.encode_png().unwrap();
+    let image_svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10">
+<image href="img/dot.png" width="20" height="10" preserveAspectRatio="none"/></svg>"#;
+    let post_resource = |name: &str| {
+        client
+            .post("/image")
+            .header(multipart_type())
+            .header(Header::new("x-api-key", "XO"))
+            .body(multipart(
+                &[],
+                &[
+                    ("svg", "main.svg", image_svg),
+                    (&format!("resources[{name}]"), "dot.png", &dot),
+                ],
+            ))
+    };
+    let response = post_resource("./img//dot.png").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let png = response.into_bytes().await.expect("body");
+    let pixmap = tiny_skia::Pixmap::decode_png(&png).unwrap();
+    assert_eq!(
+        pixmap.pixel(10, 5).unwrap().demultiply(),
+        tiny_skia::ColorU8::from_rgba(0, 0, 255, 255)
+    );
+    for name in ["../../etc/x", "/etc/x", "img/../../x", "main.svg"] {
+        let response = post_resource(name).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest, "{name}");
+        let error: Value = response.into_json().await.expect("json");
+        assert_eq!(error["error"], "invalid_resource_name");
+        assert_eq!(error["resource"], name);
+    }
+
+    // the same render from a json body, with the resource inline or stored
+    let response = upload(Method::Post, "/templates")
+        .body(multipart(
+            &[],
+            &[
+                ("svg", "main.svg", image_svg),
+                ("resources[img/dot.png]", "dot.png", &dot),
+            ],
+        ))
+        .dispatch()
+        .await;
+    let stored: Value = response.into_json().await.expect("json");
+    let post_json = |body: Value| {
+        client
+            .post("/image")
+            .header(ContentType::JSON)
+            .header(Header::new("x-api-key", "XO"))
+            .body(body.to_string())
+    };
+    use base64::Engine;
+    let inline = json!({"base64": base64::engine::general_purpose::STANDARD.encode(&dot)});
+    let by_reference = json!({"template": {"id": stored["id"]}});
+    let renamed = json!({"template": {"id": stored["id"], "name": "./img/dot.png"}});
+    for resource in [inline, by_reference, renamed] {
+        let response = post_json(json!({
+            "svg": std::str::from_utf8(image_svg).unwrap(),
+            "resources": {"img/dot.png": resource},
+            "width": 40,
+            "format": "png",
+        }))
+        .dispatch()
+        .await;
+        assert_eq!(response.status(), Status::Ok);
+        let png = response.into_bytes().await.expect("body");
+        let pixmap = tiny_skia::Pixmap::decode_png(&png).unwrap();
+        assert_eq!(pixmap.width(), 40);
+        assert_eq!(
+            pixmap.pixel(20, 10).unwrap().demultiply(),
+            tiny_skia::ColorU8::from_rgba(0, 0, 255, 255)
+        );
+    }
+    let response = post_json(json!({
+        "svg": SQUARE_SVG,
+        "vars": {"unused": "x"},
+        "variants": [{"preset": "og"}, {"width": 10, "format": "webp", "name": "small"}],
+        "archive": "zip",
+    }))
+    .dispatch()
+    .await;
+    assert_eq!(response.content_type(), Some(ContentType::ZIP));
+    let errors = [
+        (
+            json!({"svg": SQUARE_SVG, "resources": {"img/dot.png": {"base64": "not base64!"}}}),
+            Status::UnprocessableEntity,
+            "invalid_resource",
+        ),
+        (
+            json!({"svg": SQUARE_SVG, "resources": {"x.png": {"template": {"id": stored["id"]}}}}),
+            Status::UnprocessableEntity,
+            "invalid_resource",
+        ),
+        (
+            json!({"svg": SQUARE_SVG, "variants": [{"quality": 0}]}),
+            Status::UnprocessableEntity,
+            "invalid_field",
+        ),
+        (
+            json!({"svg": SQUARE_SVG, "background": "not a colour"}),
+            Status::UnprocessableEntity,
+            "invalid_json",
+        ),
+        (
+            json!({"width": 10}),
+            Status::UnprocessableEntity,
+            "invalid_json",
+        ),
+    ];
+    for (body, status, error) in errors {
+        let response = post_json(body).dispatch().await;
+        assert_eq!(response.status(), status);
+        let body: Value = response.into_json().await.expect("json");
+        assert_eq!(body["error"], error, "{body}");
+    }
+    let response = client
+        .post("/image")
+        .header(ContentType::JSON)
+        .header(Header::new("x-api-key", "XO"))
+        .body("{not json")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    // limits
+    let limit_hit = |status: Status, error: &'static str, limit: &'static str| {
+        move |response: Value| {
+            assert_eq!(response["error"], error, "{response}");
+            assert_eq!(response["limit"], limit);
+            status
+        }
+    };
+    let checks: Vec<(LocalRequest, _)> =
+        vec![
+        (
+            post_resource("big.png").body(multipart(
+                &[],
+                &[
+                    ("svg", "main.svg", image_svg),
+                    ("resources[big.png]", "big.png", &[0u8; 2048]),
+                ],
+            )),
+            limit_hit(Status::PayloadTooLarge, "resource_too_large", "max_resource_bytes"),
+        ),
+        (
+            post_resource("many").body(multipart(
+                &[],
+                &[
+                    ("svg", "main.svg", image_svg),
+                    ("resources[a]", "a", b"a"),
+                    ("resources[b]", "b", b"b"),
+                    ("resources[c]", "c", b"c"),
+                    ("resources[d]", "d", b"d"),
+                    ("resources[e]", "e", b"e"),
+                ],
+            )),
+            limit_hit(Status::PayloadTooLarge, "too_many_resources", "max_resources"),
+        ),
+        (
+            post_resource("huge").body(multipart(
+                &[],
+                &[("svg", "main.svg", &vec![b' '; 300 * 1024])],
+            )),
+            limit_hit(Status::PayloadTooLarge, "request_too_large", "max_request_bytes"),
+        ),
+        (
+            post_square(&client, &[("width", "5000")]),
+            limit_hit(Status::UnprocessableEntity, "output_too_large", "max_width"),
+        ),
+        (
+            post_square(&client, &[("width", "3000"), ("height", "3000")]),
+            limit_hit(Status::UnprocessableEntity, "output_too_large", "max_pixels"),
+        ),
+        (
+            post_resource("nodes").body(multipart(
+                &[],
+                &[(
+                    "svg",
+                    "main.svg",
+                    format!(
+                        r#"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="1">{}</svg>"#,
+                        "<g/>".repeat(1000)
+                    )
+                    .as_bytes(),
+                )],
+            )),
+            limit_hit(Status::UnprocessableEntity, "svg_too_complex", "max_svg_nodes"),
+        ),
+    ];
+    for (request, check) in checks {
+        let response = request.dispatch().await;
+        let status = response.status();
+        assert_eq!(status, check(response.into_json().await.expect("json")));
+    }
+    // the svg's own size counts too
+    let response = post_resource("tall")
+        .body(multipart(
+            &[],
+            &[(
+                "svg",
+                "main.svg",
+                br#"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="100000"/>"#,
+            )],
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    // named keys only get their scopes, until they expire, within their own limits
+    fn with_key<'c>(key: &'static str, request: LocalRequest<'c>) -> LocalRequest<'c> {
+        request.header(Header::new("x-api-key", key))
+    }
+    let square = || {
+        client
+            .post("/image")
+            .header(multipart_type())
+            .body(multipart(
+                &[],
+                &[("svg", "main.svg", SQUARE_SVG.as_bytes())],
+            ))
+    };
+    let refused = |error: &'static str| {
+        move |body: Value| {
+            assert_eq!(body["error"], error);
+            Status::Forbidden
+        }
+    };
+    let response = with_key("N", square()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    // cached for keys that may render it, but still too wide for this one
+    let response = with_key("R", square()).dispatch().await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    for (key, check) in [
+        ("O", refused("expired_key")),
+        ("P", refused("expired_key")),
+        (
+            "sha256:8ce86a6ae65d3692e7305e2c58ac62eebd97d3d943e093f577da25c36988246b",
+            refused("invalid_key"),
+        ),
+    ] {
+        let response = with_key(key, square()).dispatch().await;
+        let status = response.status();
+        assert_eq!(status, check(response.into_json().await.expect("json")));
+    }
+    // or as a bearer token
+    let bearer = |value: &'static str| square().header(Header::new("Authorization", value));
+    assert_eq!(bearer("Bearer N").dispatch().await.status(), Status::Ok);
+    assert_eq!(bearer("bearer N").dispatch().await.status(), Status::Ok);
+    assert_eq!(
+        bearer("Bearer nope").dispatch().await.status(),
+        Status::Forbidden
+    );
+    assert_eq!(
+        bearer("Basic UjpS").dispatch().await.status(),
+        Status::Unauthorized
+    );
+    let upload = client
+        .post("/templates")
+        .header(multipart_type())
+        .body(multipart(
+            &[],
+            &[("svg", "main.svg", SQUARE_SVG.as_bytes())],
+        ));
+    let response = with_key("R", upload).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "insufficient_scope");
+    assert_eq!(error["scope"], "templates");
+    let response = with_key("R", client.get("/keys")).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let wide = client
+        .post("/image")
+        .header(multipart_type())
+        .body(multipart(
+            &[("width", "40")],
+            &[("svg", "main.svg", SQUARE_SVG.as_bytes())],
+        ));
+    let response = with_key("R", wide).dispatch().await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["max"], 10);
+
+    // a key's rate and quota: the second render is over its daily_quota of 1,
+    // and the third finds its bucket of 2 empty
+    let response = with_key("L", square()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("RateLimit-Remaining"), Some("1"));
+    let response = with_key("L", square()).dispatch().await;
+    assert_eq!(response.status(), Status::TooManyRequests);
+    assert!(response.headers().get_one("Retry-After").is_some());
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "quota_exceeded");
+    assert_eq!(error["limit"], "daily_quota");
+    let response = with_key("L", square()).dispatch().await;
+    assert_eq!(response.status(), Status::TooManyRequests);
+    assert_eq!(response.headers().get_one("RateLimit-Remaining"), Some("0"));
+    let retry_after: u64 = (response.headers().get_one("Retry-After"))
+        .and_then(|secs| secs.parse().ok())
+        .expect("Retry-After");
+    assert!(retry_after > 900);
+    let error: Value = response.into_json().await.expect("json");
+    assert_eq!(error["error"], "rate_limited");
+    // a render refused as too large isn't counted against the quota
+    let tall = client
+        .post("/image")
+        .header(multipart_type())
+        .body(multipart(
+            &[],
+            &[(
+                "svg",
+                "main.svg",
+                br#"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="100000"/>"#,
+            )],
+        ));
+    let response = with_key("Q", tall).dispatch().await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(
+        with_key("Q", square()).dispatch().await.status(),
+        Status::Ok
+    );
+    let response = with_key("Q", square()).dispatch().await;
+    assert_eq!(response.status(), Status::TooManyRequests);
+    let metrics = client.get("/metrics").dispatch().await;
+    let metrics = metrics.into_string().await.expect("metrics");
+    assert!(metrics.contains(r#"social_image_key_renders_total{key="limited"} 1"#));
+    assert!(metrics.contains(r#"social_image_key_renders_total{key="quota"} 1"#));
+    assert!(metrics.contains(r#"social_image_key_requests_total{key="limited"} 3"#));
+    assert!(
+        metrics.contains(r#"social_image_key_limited_total{key="limited",limit="daily_quota"} 1"#)
+    );
+    assert!(metrics.contains(r#"social_image_key_limited_total{key="limited",limit="rate"} 1"#));
+    assert!(metrics.contains("social_image_signed_limited_total 2"));
+
+    let response = with_key("XO", client.get("/keys")).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let keys: Value = response.into_json().await.expect("json");
+    assert_eq!(keys["renderer"]["scopes"], json!(["render"]));
+    assert_eq!(keys["renderer"]["limits"]["max_width"], 10);
+    assert_eq!(keys["key"]["scopes"], json!(["admin"]));
+    assert!(keys["old"]["expires"].is_string());
+    assert!(!keys.to_string().contains("\"XO\""));
+    assert!(!keys.to_string().contains("8ce86a6a"));
+
+    let response = client
+        .get("/fonts")
+        .header(Header::new("x-api-key", "XO"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let fonts: Value = response.into_json().await.expect("json");
+    assert!(fonts["faces"].is_array());
+    assert!(fonts["generic"]["serif"].is_string());
+
+    // past MAX_BUCKETS, buckets still being refilled are forgotten too
+    let rates = client.rocket().state::<RateLimiter>().expect("rates");
+    let limits = apikey::KeyLimits {
+        rate_per_sec: Some(0.001),
+        rate_burst: Some(1),
+        ..apikey::KeyLimits::default()
+    };
+    for i in 0..=ratelimit::MAX_BUCKETS {
+        let request = client.get("/");
+        assert!(rates.key_request(request.inner(), &format!("flood-{i}"), &limits));
+    }
+    let metrics = client.get("/metrics").dispatch().await;
+    let metrics = metrics.into_string().await.expect("metrics");
+    let buckets = format!("social_image_rate_buckets {}", ratelimit::MAX_BUCKETS);
+    assert!(metrics.contains(&buckets));
+
+    let _ = std::fs::remove_dir_all(scratch);
+
     // let req = client
     //     .post("/image")
     //     .header(ContentType::new("multipart", "form-data"))
@@ -44,3 +859,854 @@ async fn tests() {
     // let response3 = req.dispatch();
     // assert_eq!(response3.status(), Status::BadRequest);
 }
+
+#[test]
+fn fonts_are_detected_by_content() {
+    assert!(fonts::is_font(b"\x00\x01\x00\x00rest"));
+    assert!(fonts::is_font(b"OTTO\x00\x0a"));
+    assert!(fonts::is_font(b"ttcf\x00\x01"));
+    assert!(!fonts::is_font(b"\x89PNG\r\n"));
+    assert!(!fonts::is_font(b"OTT"));
+
+    let unresolved = FontResolution {
+        requested: vec!["\"Br\u{e5}nd\"".into(), "serif".into()],
+        family: None,
+        face: None,
+    };
+    assert_eq!(unresolved.to_header_value(), "\"Br?nd\", serif => none");
+}
+
+#[test]
+fn template_substitution() {
+    let vars: HashMap<String, String> =
+        [("title".to_string(), "Tom & \"Jerry\"".to_string())].into();
+    assert_eq!(
+        template::substitute("<text>{{ title }}</text>", &vars).unwrap(),
+        "<text>Tom &amp; &quot;Jerry&quot;</text>"
+    );
+    assert_eq!(
+        template::substitute("<text>{{author | Anon <me>}}</text>", &vars).unwrap(),
+        "<text>Anon &lt;me&gt;</text>"
+    );
+    assert_eq!(
+        template::substitute("<style>a{{color:red}}</style>{{", &vars).unwrap(),
+        "<style>a{{color:red}}</style>{{"
+    );
+    let error = template::substitute("{{b}}{{a}}{{b}}{{title}}", &vars).unwrap_err();
+    assert_eq!(error.status, Status::UnprocessableEntity);
+    assert_eq!(error.message, "no value for template variables: a, b");
+}
+
+#[test]
+fn text_fits_its_box() {
+    // with no fonts every character is estimated at half an em
+    let db = usvg::fontdb::Database::new();
+    let fit = |attrs: &str| {
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><text x="5" font-size="20" data-fit-width="100" {attrs}>aaaa bbbb  cccc</text></svg>"#
+        );
+        text::fit(&svg, &db, Deadline::default())
+            .unwrap()
+            .expect("fitted")
+    };
+    assert!(fit("").contains(
+        r#"<tspan x="5" dy="0" font-size="20">aaaa bbbb</tspan><tspan x="5" dy="24" font-size="20">cccc</tspan>"#
+    ));
+    assert!(fit(r#"data-max-lines="1" data-min-font-size="10""#)
+        .contains(r#"<tspan x="5" dy="0" font-size="14">aaaa bbbb cccc</tspan></text>"#));
+    assert!(fit(r#"data-max-lines="1" data-min-font-size="18""#)
+        .contains(">aaaa bbbb\u{2026}</tspan></text>"));
+    assert!(fit(r#"data-fit-height="30""#).contains(">aaaa bbbb\u{2026}</tspan></text>"));
+    assert_eq!(
+        text::fit("<svg><text>plain</text></svg>", &db, Deadline::default()).unwrap(),
+        None
+    );
+    // without an x every line starts where the text would, at 0
+    let fitted = text::fit(
+        r#"<svg><text font-size="20" data-fit-width="100">aaaa bbbb cccc</text></svg>"#,
+        &db,
+        Deadline::default(),
+    )
+    .unwrap()
+    .expect("fitted");
+    assert!(fitted.contains(r#"<tspan x="0" dy="24" font-size="20">cccc</tspan>"#));
+
+    // a huge size is clamped, and shrinking it takes a bounded number of steps
+    let huge = r#"<svg><text font-size="1e30" data-min-font-size="1e-30" data-fit-width="100" data-max-lines="1">aaaa bbbb cccc</text></svg>"#;
+    let fitted = text::fit(huge, &db, Deadline::default())
+        .unwrap()
+        .expect("fitted");
+    assert!(fitted.contains(r#"font-size="14">aaaa bbbb cccc</tspan>"#));
+    let too_long = huge.replace("aaaa bbbb cccc", &"aaaa ".repeat(10_000));
+    assert!(text::fit(&too_long, &db, Deadline::default())
+        .unwrap()
+        .expect("fitted")
+        .contains('\u{2026}'));
+    // and it stops at the deadline
+    assert!(text::fit(huge, &db, Deadline::after(Duration::ZERO)).is_err());
+}
+
+#[async_test]
+async fn file_store_round_trip() {
+    let root = std::env::temp_dir().join(format!("social-image-store-{}", SvgId::new()));
+    let store = FileStore::new(&root).await.expect("store");
+    let package = SvgPackage {
+        svg: SQUARE_SVG.as_bytes().to_vec(),
+        resources: [
+            ("logo.png".to_string(), vec![1, 2, 3]),
+            ("img/photo.jpg".to_string(), vec![4, 5]),
+        ]
+        .into(),
+    };
+
+    let id = store.save(&package).await.expect("save");
+    assert_eq!(id.to_string().parse::<SvgId>().ok(), Some(id.clone()));
+    assert_eq!(store.load(&id).await.expect("load"), package);
+
+    // updates land one at a time, whole, and readers never find the svg missing
+    let store = std::sync::Arc::new(store);
+    let updated = |i: usize| SvgPackage {
+        svg: format!("<svg>{i}</svg>").into_bytes(),
+        resources: HashMap::from([(format!("{i}.png"), vec![1])]),
+    };
+    let mut tasks = Vec::new();
+    for i in 0..8 {
+        let (store, id) = (store.clone(), id.clone());
+        tasks.push(tokio::spawn(async move {
+            store.update(&id, &updated(i)).await.expect("update");
+            let loaded = store.load(&id).await.expect("load while updating");
+            assert!((0..8).any(|i| loaded == updated(i)));
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let leftovers = std::fs::read_dir(&root).unwrap().count();
+    assert_eq!(leftovers, 1, "only the svg's own directory remains");
+    store.delete(&id).await.expect("delete");
+    let missing = store.load(&id).await.unwrap_err();
+    assert_eq!(ApiError::from_report(missing).status, Status::NotFound);
+    assert!(store.delete(&id).await.is_err());
+
+    let escape = SvgPackage {
+        resources: [("../outside".to_string(), vec![])].into(),
+        ..package
+    };
+    assert!(store.save(&escape).await.is_err());
+    assert!(!root.join("outside").exists());
+    assert!("../etc".parse::<SvgId>().is_err());
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn signed_urls() {
+    let config = AppConfig {
+        signing_key: Some("a secret".into()),
+        ..AppConfig::default()
+    };
+    let path = "/templates/abc/render.png";
+    let params = [("title", "a b&c"), ("author", "me")];
+    let url = signed::sign(&config, path, &params, "ci", None).unwrap();
+    let query = |url: &str| {
+        url.split_once('?')
+            .unwrap()
+            .1
+            .split('&')
+            .map(|pair| pair.split_once('=').unwrap())
+            .map(|(k, v)| (k.to_string(), v.replace("%20", " ").replace("%26", "&")))
+            .collect::<Vec<_>>()
+    };
+    let verify = |path, params: &[(String, String)]| {
+        signed::verify(
+            &config,
+            path,
+            params.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+        )
+        .map(str::to_string)
+    };
+    let params = query(&url);
+    assert_eq!(verify(path, &params).unwrap(), "ci");
+    // the signer is signed too
+    let other_signer: Vec<_> = params
+        .iter()
+        .map(|(k, v)| match k.as_str() {
+            "signer" => (k.clone(), "admin".to_string()),
+            _ => (k.clone(), v.clone()),
+        })
+        .collect();
+    assert!(verify(path, &other_signer).is_err());
+    assert!(verify("/templates/abd/render.png", &params).is_err());
+    let mut reordered = params.clone();
+    reordered.reverse();
+    assert!(verify(path, &reordered).is_ok());
+    assert!(verify(path, &params[1..]).is_err());
+
+    let past = time::OffsetDateTime::now_utc().unix_timestamp() - 1;
+    let expired = signed::sign(&config, path, &[], "ci", Some(past)).unwrap();
+    assert!(matches!(
+        verify(path, &query(&expired)),
+        Err(signed::SignatureError::Expired)
+    ));
+    let other_key = AppConfig {
+        signing_key: Some("another secret".into()),
+        ..AppConfig::default()
+    };
+    assert!(signed::verify(
+        &other_key,
+        path,
+        params.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    )
+    .is_err());
+
+    // without a secret nothing is signed, and no signature is accepted
+    for unsigned in [
+        AppConfig::default(),
+        AppConfig {
+            key: Some(String::new()),
+            signing_key: Some(String::new()),
+            ..AppConfig::default()
+        },
+        // a hash is in the config for anyone to sign with
+        AppConfig {
+            key: Some(apikey::generate().1),
+            ..AppConfig::default()
+        },
+    ] {
+        assert!(!signed::enabled(&unsigned));
+        assert!(signed::sign(&unsigned, path, &[], "ci", None).is_err());
+        assert!(matches!(
+            signed::verify(
+                &unsigned,
+                path,
+                params.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+            ),
+            Err(signed::SignatureError::Disabled)
+        ));
+    }
+}
+
+#[async_test]
+async fn render_cache() {
+    let dir = std::env::temp_dir().join(format!("social-image-cache-{}", SvgId::new()));
+    let registry = Registry::new();
+    let package = SvgPackage {
+        svg: SQUARE_SVG.as_bytes().to_vec(),
+        ..SvgPackage::default()
+    };
+    let rendered = |data: &[u8]| {
+        let data = data.to_vec();
+        async move {
+            Ok(Rendered {
+                content_type: ContentType::PNG,
+                data,
+                fonts: Vec::new(),
+            })
+        }
+    };
+    let fonts = FontLibrary::from_config(&AppConfig::default());
+    let limits = AppConfig::default().limits();
+    let key = |output: &str| CacheKey::new(&package, &HashMap::new(), &fonts, &limits, &output);
+    assert_ne!(key("a"), key("b"));
+    // nor may a render be reused for a request held to smaller limits
+    let smaller = Limits {
+        max_width: 16,
+        ..limits
+    };
+    assert_ne!(
+        key("a"),
+        CacheKey::new(&package, &HashMap::new(), &fonts, &smaller, &"a")
+    );
+    // a server with other fonts may render the same input differently
+    let more_fonts = FontLibrary::from_config(&AppConfig {
+        font_dirs: vec![concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/fonts").into()],
+        ..AppConfig::default()
+    });
+    assert_eq!(more_fonts.faces().len(), 1);
+    assert_ne!(
+        key("a"),
+        CacheKey::new(&package, &HashMap::new(), &more_fonts, &limits, &"a")
+    );
+    // targets are keyed by their fields, so only a change to one is a new entry
+    let target = |quality| Target {
+        sizing: Sizing::default(),
+        encoding: Encoding {
+            quality,
+            ..Encoding::default()
+        },
+    };
+    let key_for =
+        |target: &Target| CacheKey::new(&package, &HashMap::new(), &fonts, &limits, target);
+    assert_eq!(key_for(&target(80)), key_for(&target(80)));
+    assert_ne!(key_for(&target(80)), key_for(&target(81)));
+    assert_ne!(
+        key_for(&target(80)),
+        CacheKey::new(
+            &package,
+            &HashMap::new(),
+            &fonts,
+            &limits,
+            &(ArchiveFormat::Zip, vec![("og".to_string(), target(80))])
+        )
+    );
+
+    let cache = RenderCache::new(&dir, Duration::from_secs(60), 300, &registry).expect("cache");
+    let first = cache
+        .get_or_render(&key("a"), rendered(&[1; 100]))
+        .await
+        .unwrap();
+    let again = cache
+        .get_or_render(&key("a"), rendered(&[2; 100]))
+        .await
+        .unwrap();
+    assert_eq!(again.data, first.data);
+    assert_eq!(again.content_type, ContentType::PNG);
+
+    // filling the cache evicts the least recently used
+    cache
+        .get_or_render(&key("b"), rendered(&[3; 100]))
+        .await
+        .unwrap();
+    cache
+        .get_or_render(&key("a"), rendered(&[4; 100]))
+        .await
+        .unwrap();
+    cache
+        .get_or_render(&key("c"), rendered(&[5; 100]))
+        .await
+        .unwrap();
+    let a = cache
+        .get_or_render(&key("a"), rendered(&[6; 100]))
+        .await
+        .unwrap();
+    assert_eq!(a.data, vec![1; 100]);
+    let b = cache
+        .get_or_render(&key("b"), rendered(&[7; 100]))
+        .await
+        .unwrap();
+    assert_eq!(b.data, vec![7; 100]);
+    let families = registry.gather();
+    let count = |name: &str| {
+        let family = families.iter().find(|f| f.get_name() == name).unwrap();
+        family.get_metric()[0].get_counter().get_value()
+    };
+    assert_eq!(count("social_image_render_cache_hits_total"), 3.0);
+    assert_eq!(count("social_image_render_cache_misses_total"), 4.0);
+
+    // entries outlive a restart, but not their ttl
+    drop(cache);
+    let reopened = RenderCache::new(&dir, Duration::from_secs(60), 300, &Registry::new()).unwrap();
+    let b = reopened
+        .get_or_render(&key("b"), rendered(&[8; 100]))
+        .await
+        .unwrap();
+    assert_eq!(b.data, vec![7; 100]);
+    let expiring = RenderCache::new(&dir, Duration::from_nanos(1), 300, &Registry::new()).unwrap();
+    let b = expiring
+        .get_or_render(&key("b"), rendered(&[9; 100]))
+        .await
+        .unwrap();
+    assert_eq!(b.data, vec![9; 100]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[async_test]
+async fn render_timeout() {
+    let registry = Registry::new();
+    let pool = RenderPool::new(1, 1, &registry).unwrap();
+    let renderer = Renderer::new(
+        pool,
+        0,
+        std::env::temp_dir(),
+        Duration::from_millis(50),
+        &registry,
+    )
+    .unwrap();
+    let root = std::env::temp_dir().join(format!("social-image-timeout-{}", SvgId::new()));
+    let space = RenderSpace::new(&root).unwrap();
+    let path = space.as_ref().to_path_buf();
+
+    let slow = async move {
+        let _space = space;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        Ok(())
+    };
+    let error = ApiError::from_report(renderer.timed(|_| slow).await.unwrap_err());
+    assert_eq!(error.status, Status::GatewayTimeout);
+    assert_eq!(error.error, "render_timeout");
+    assert!(!path.exists(), "the render space is removed");
+    let timeouts = registry
+        .gather()
+        .into_iter()
+        .find(|family| family.get_name() == "social_image_render_timeouts_total")
+        .unwrap();
+    assert_eq!(timeouts.get_metric()[0].get_counter().get_value(), 1.0);
+
+    // work on a render thread stops at its next step past the deadline
+    let stopped = renderer
+        .timed(|deadline| async move {
+            std::thread::sleep(Duration::from_millis(60));
+            deadline.check()
+        })
+        .await;
+    let error = ApiError::from_report(stopped.unwrap_err());
+    assert_eq!(error.error, "render_timeout");
+    let timeouts = registry
+        .gather()
+        .into_iter()
+        .find(|family| family.get_name() == "social_image_render_timeouts_total")
+        .unwrap();
+    assert_eq!(timeouts.get_metric()[0].get_counter().get_value(), 2.0);
+    let package = SvgPackage {
+        svg: SQUARE_SVG.as_bytes().to_vec(),
+        ..SvgPackage::default()
+    };
+    let fonts = FontLibrary::from_config(&AppConfig::default());
+    let (vars, limits) = (HashMap::new(), AppConfig::default().limits());
+    let parse = |deadline| render::parse_svg(&package, &vars, &fonts, &limits, &renderer, deadline);
+    assert!(parse(Deadline::after(Duration::ZERO)).await.is_err());
+    assert!(parse(Deadline::default()).await.is_ok());
+
+    let fast = renderer.timed(|_| async { Ok(1) }).await.unwrap();
+    assert_eq!(fast, 1);
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[async_test]
+async fn renders_with_uploaded_fonts() {
+    let registry = Registry::new();
+    let config = AppConfig::default();
+    let pool = RenderPool::new(1, 1, &registry).unwrap();
+    let renderer = Renderer::new(
+        pool,
+        u64::MAX,
+        std::env::temp_dir(),
+        Duration::ZERO,
+        &registry,
+    )
+    .unwrap();
+    let fonts = FontLibrary::from_config(&config);
+    let target = Target {
+        sizing: Sizing::default(),
+        encoding: Encoding::default(),
+    };
+    let render = |resources: HashMap<String, Vec<u8>>| {
+        let package = SvgPackage {
+            svg: br#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="40">
+<text x="0" y="36" font-family="Tuffy" font-size="40">H</text></svg>"#
+                .to_vec(),
+            resources,
+        };
+        let (target, fonts, renderer) = (&target, &fonts, &renderer);
+        let limits = config.limits();
+        async move {
+            render::image_from_svg(&package, &HashMap::new(), target, fonts, &limits, renderer)
+                .await
+                .unwrap()
+        }
+    };
+    let inked = |rendered: &Rendered| {
+        let pixmap = tiny_skia::Pixmap::decode_png(&rendered.data).unwrap();
+        pixmap.pixels().iter().any(|pixel| pixel.alpha() > 0)
+    };
+
+    // the library is empty, so without the upload there is nothing to draw with
+    let missing = render(HashMap::new()).await;
+    assert_eq!(missing.fonts[0].family, None);
+    assert!(!inked(&missing));
+
+    let tuffy = include_bytes!("../fixtures/fonts/Tuffy.ttf").to_vec();
+    let uploaded = render(HashMap::from([("fonts/brand.bin".to_string(), tuffy)])).await;
+    assert_eq!(uploaded.fonts[0].family.as_deref(), Some("Tuffy"));
+    assert!(inked(&uploaded));
+    // the upload stays with its own render
+    assert!(fonts.faces().is_empty());
+}
+
+#[async_test]
+async fn compressed_svgs_are_bounded() {
+    use std::io::Write;
+    let gzip = |data: &[u8]| {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    };
+    let registry = Registry::new();
+    let pool = RenderPool::new(1, 1, &registry).unwrap();
+    let renderer = Renderer::new(
+        pool,
+        u64::MAX,
+        std::env::temp_dir(),
+        Duration::ZERO,
+        &registry,
+    )
+    .unwrap();
+    let fonts = FontLibrary::from_config(&AppConfig::default());
+    let limits = AppConfig {
+        max_request_bytes: 4096,
+        ..AppConfig::default()
+    }
+    .limits();
+    let parse = |svg: Vec<u8>| async {
+        let package = SvgPackage {
+            svg,
+            ..SvgPackage::default()
+        };
+        let vars = HashMap::new();
+        render::parse_svg(
+            &package,
+            &vars,
+            &fonts,
+            &limits,
+            &renderer,
+            Deadline::default(),
+        )
+        .await
+        .map(|_| ())
+    };
+    assert!(parse(gzip(SQUARE_SVG.as_bytes())).await.is_ok());
+    // a few kilobytes that expand far past max_request_bytes
+    let mut bomb = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    bomb.write_all(SQUARE_SVG.replace("</svg>", "<!--").as_bytes())
+        .unwrap();
+    for _ in 0..16 {
+        bomb.write_all(&[b' '; 1 << 20]).unwrap();
+    }
+    let bomb = bomb.finish().unwrap();
+    assert!(bomb.len() < 32 * 1024);
+    let error = ApiError::from_report(parse(bomb).await.unwrap_err());
+    assert_eq!(error.status, Status::PayloadTooLarge);
+    assert_eq!(error.error, "svg_too_large");
+    assert!(error
+        .details
+        .contains(&("limit", json!("max_request_bytes"))));
+}
+
+#[async_test]
+async fn renders_from_memory() {
+    let config = AppConfig::default();
+    let fonts = FontLibrary::from_config(&config);
+    let target = Target {
+        sizing: Sizing::default(),
+        encoding: Encoding::default(),
+    };
+
+    let mut dot = tiny_skia::Pixmap::new(1, 1).unwrap();
+    dot.fill(tiny_skia::Color::from_rgba8(0, 0, 255, 255));
+    let dot = dot.encode_png().unwrap();
+    // a file outside the package, which no render may reach
+    let outside = std::env::temp_dir().join(format!("social-image-dot-{}.png", SvgId::new()));
+    std::fs::write(&outside, &dot).unwrap();
+    let svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10">
+<image href="./img/dot.png" width="10" height="10" preserveAspectRatio="none"/>
+<image href="{}" x="10" width="10" height="10" preserveAspectRatio="none"/></svg>"#,
+        outside.display()
+    );
+    let package = SvgPackage {
+        svg: svg.into_bytes(),
+        resources: HashMap::from([("img/dot.png".to_string(), dot)]),
+    };
+
+    // from memory, and through the disk
+    for memory_max_bytes in [u64::MAX, 0] {
+        let registry = Registry::new();
+        let pool = RenderPool::new(1, 1, &registry).unwrap();
+        let renderer = Renderer::new(
+            pool,
+            memory_max_bytes,
+            std::env::temp_dir(),
+            Duration::ZERO,
+            &registry,
+        )
+        .unwrap();
+        let rendered = render::image_from_svg(
+            &package,
+            &HashMap::new(),
+            &target,
+            &fonts,
+            &config.limits(),
+            &renderer,
+        )
+        .await
+        .unwrap();
+        let pixmap = tiny_skia::Pixmap::decode_png(&rendered.data).unwrap();
+        let blue = tiny_skia::ColorU8::from_rgba(0, 0, 255, 255);
+        assert_eq!(pixmap.pixel(5, 5).unwrap().demultiply(), blue);
+        assert_eq!(pixmap.pixel(15, 5).unwrap().alpha(), 0);
+    }
+    std::fs::remove_file(outside).unwrap();
+}
+
+#[async_test]
+async fn render_pool_is_bounded() {
+    let registry = Registry::new();
+    let pool = std::sync::Arc::new(RenderPool::new(1, 1, &registry).unwrap());
+    let depth = || {
+        let family = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "social_image_render_queue_depth")
+            .unwrap();
+        family.get_metric()[0].get_gauge().get_value()
+    };
+    let (started, running) = std::sync::mpsc::channel();
+    let (release, held) = std::sync::mpsc::channel::<()>();
+    let first = tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            pool.run(move || {
+                started.send(()).unwrap();
+                held.recv().unwrap();
+                Ok(1)
+            })
+            .await
+        }
+    });
+    running.recv_timeout(Duration::from_secs(5)).unwrap();
+    let second = tokio::spawn({
+        let pool = pool.clone();
+        async move { pool.run(|| Ok(2)).await }
+    });
+    while depth() < 1.0 {
+        tokio::task::yield_now().await;
+    }
+
+    // the only thread is busy and the queue is full
+    let error = ApiError::from_report(pool.run(|| Ok(2)).await.unwrap_err());
+    assert_eq!(error.status, Status::ServiceUnavailable);
+    assert_eq!(error.error, "busy");
+    assert_eq!(error.headers, vec![("Retry-After", "1".to_string())]);
+    // and a render turned away doesn't use up its key's quota
+    let config = AppConfig {
+        key: Some("K".into()),
+        daily_quota: Some(1),
+        ..AppConfig::default()
+    };
+    let keys = apikey::Keys::from_config(&config).unwrap();
+    let key = keys.named::<apikey::RenderScope>("key").unwrap();
+    let rates = RateLimiter::new(&config, &Registry::new()).unwrap();
+    let charged = |pool: std::sync::Arc<RenderPool>| {
+        let render = async move { pool.run(|| Ok(3)).await };
+        key.count_render(&rates, async {
+            render.await.map_err(ApiError::from_report)
+        })
+    };
+    let error = charged(pool.clone()).await.unwrap_err();
+    assert_eq!(error.status, Status::ServiceUnavailable);
+
+    release.send(()).unwrap();
+    assert_eq!(first.await.unwrap().unwrap(), 1);
+    assert_eq!(second.await.unwrap().unwrap(), 2);
+    assert_eq!(depth(), 0.0);
+    assert_eq!(charged(pool.clone()).await.unwrap(), 3);
+    let error = charged(pool.clone()).await.unwrap_err();
+    assert_eq!(error.error, "quota_exceeded");
+
+    // a panic fails only its own render
+    assert!(pool.run::<()>(|| panic!("bad svg")).await.is_err());
+    assert_eq!(pool.run(|| Ok(3)).await.unwrap(), 3);
+}
+
+#[test]
+fn resource_names_stay_inside() {
+    assert_eq!(resource_name("logo.png").unwrap(), "logo.png");
+    assert_eq!(resource_name("./img//logo.png").unwrap(), "img/logo.png");
+    assert_eq!(resource_name("img\\logo.png").unwrap(), "img/logo.png");
+    for name in [
+        "",
+        ".",
+        "/etc/passwd",
+        "..",
+        "a/../../b",
+        "C:\\x",
+        "a\0b",
+        "MAIN.svg",
+    ] {
+        let error = resource_name(name).unwrap_err();
+        assert_eq!(error.status, Status::BadRequest, "{name:?}");
+    }
+}
+
+#[test]
+fn output_format_negotiation() {
+    let archive = |accept: &str| ArchiveFormat::negotiate(Some(&accept.parse().unwrap()));
+    assert_eq!(ArchiveFormat::negotiate(None), Some(ArchiveFormat::Zip));
+    assert_eq!(archive("multipart/mixed"), Some(ArchiveFormat::Multipart));
+    assert_eq!(archive("image/png"), None);
+
+    let negotiate = |accept: &str| OutputFormat::negotiate(Some(&accept.parse().unwrap()));
+    assert_eq!(OutputFormat::negotiate(None), Some(OutputFormat::Png));
+    assert_eq!(negotiate("image/webp"), Some(OutputFormat::WebP));
+    assert_eq!(
+        negotiate("image/png;q=0.5, image/jpeg"),
+        Some(OutputFormat::Jpeg)
+    );
+    assert_eq!(negotiate("text/html, */*;q=0.1"), Some(OutputFormat::Png));
+    assert_eq!(negotiate("application/pdf"), Some(OutputFormat::Pdf));
+    assert_eq!(negotiate("image/webp;q=0, text/html"), None);
+}
+
+#[test]
+fn sizing_layout() {
+    let svg = usvg::Size::from_wh(200.0, 100.0).unwrap();
+    let layout = |width, height, fit| {
+        let sizing = Sizing {
+            width,
+            height,
+            zoom: None,
+            fit,
+        };
+        let (size, ts) = sizing.layout(svg).unwrap();
+        ((size.width(), size.height()), (ts.sx, ts.sy, ts.tx, ts.ty))
+    };
+
+    assert_eq!(
+        layout(None, None, FitMode::Contain),
+        ((200, 100), (1.0, 1.0, 0.0, 0.0))
+    );
+    assert_eq!(
+        layout(Some(100), None, FitMode::Contain),
+        ((100, 50), (0.5, 0.5, 0.0, 0.0))
+    );
+    assert_eq!(
+        layout(Some(100), Some(100), FitMode::Contain),
+        ((100, 100), (0.5, 0.5, 0.0, 25.0))
+    );
+    assert_eq!(
+        layout(Some(100), Some(100), FitMode::Cover),
+        ((100, 100), (1.0, 1.0, -50.0, 0.0))
+    );
+    assert_eq!(
+        layout(Some(100), Some(100), FitMode::Stretch),
+        ((100, 100), (0.5, 1.0, 0.0, 0.0))
+    );
+    assert_eq!(
+        layout(Some(100), Some(50), FitMode::Exact),
+        ((100, 50), (1.0, 1.0, -50.0, -25.0))
+    );
+
+    let zoomed = Sizing {
+        height: Some(50),
+        zoom: Some(2.0),
+        ..Sizing::default()
+    };
+    let (size, ts) = zoomed.layout(svg).unwrap();
+    assert_eq!((size.width(), size.height()), (200, 100));
+    assert_eq!((ts.sx, ts.sy), (1.0, 1.0));
+}
+
+#[test]
+fn presets_can_be_overridden() {
+    let custom = Preset {
+        width: 100,
+        height: 100,
+        fit: FitMode::Contain,
+        format: Some(OutputFormat::WebP),
+        max_bytes: None,
+    };
+    let configured = HashMap::from([
+        ("og".to_string(), custom.clone()),
+        ("square".to_string(), custom.clone()),
+    ]);
+    let presets = Presets::new(&configured);
+    assert_eq!(presets.get("og"), Some(&custom));
+    assert_eq!(presets.get("square"), Some(&custom));
+    assert_eq!(presets.get("twitter").map(|p| p.height), Some(675));
+    assert_eq!(presets.get("nope"), None);
+
+    // configured presets cover by default, like the built in ones
+    let banner: Preset =
+        rocket::serde::json::from_value(json!({"width": 600, "height": 200})).unwrap();
+    assert_eq!(banner.fit, FitMode::Cover);
+    assert_eq!(banner.fit, presets.get("twitter").unwrap().fit);
+}
+
+#[test]
+fn encoding_respects_max_bytes() {
+    let mut pixmap = tiny_skia::Pixmap::new(64, 64).unwrap();
+    for (i, p) in pixmap.data_mut().iter_mut().enumerate() {
+        *p = (i * 7919 % 251) as u8 | 3;
+    }
+    let encoding = Encoding {
+        format: OutputFormat::Png,
+        max_bytes: Some(100),
+        ..Encoding::default()
+    };
+    let error = encode::encode(pixmap.clone(), &encoding).unwrap_err();
+    assert_eq!(error.downcast::<ApiError>().unwrap().error, "too_large");
+
+    let jpeg = Encoding {
+        format: OutputFormat::Jpeg,
+        ..Encoding::default()
+    };
+    let unlimited = encode::encode(pixmap.clone(), &jpeg).unwrap();
+    let max_bytes = Some(unlimited.len() - 1);
+    let limited = encode::encode(pixmap, &Encoding { max_bytes, ..jpeg }).unwrap();
+    assert!(limited.len() < unlimited.len());
+}
+
+#[test]
+fn jpeg_backgrounds_are_opaque() {
+    let opaque = |css: &str| css.parse::<Background>().unwrap().opaque().0.to_color_u8();
+    let white = tiny_skia::ColorU8::from_rgba(255, 255, 255, 255);
+    assert_eq!(opaque("transparent"), white);
+    assert_eq!(
+        opaque("#336699"),
+        tiny_skia::ColorU8::from_rgba(0x33, 0x66, 0x99, 255)
+    );
+    assert_eq!(opaque("rgba(0, 0, 0, 0.5)").alpha(), 255);
+    assert!((127..=128).contains(&opaque("rgba(0, 0, 0, 0.5)").red()));
+
+    let jpeg = Encoding {
+        format: OutputFormat::Jpeg,
+        background: Some("transparent".parse().unwrap()),
+        ..Encoding::default()
+    };
+    let pixmap = tiny_skia::Pixmap::new(8, 8).unwrap();
+    assert!(encode::encode(pixmap, &jpeg)
+        .unwrap()
+        .starts_with(b"\xff\xd8"));
+}
+
+#[test]
+fn generated_keys_match_their_hash() {
+    let (key, hash) = apikey::generate();
+    assert!(apikey::is_hash(&hash));
+    assert!(!apikey::is_hash(&key));
+    assert_eq!(
+        format!("{:?}", apikey::Secret::parse(&key, None).unwrap()),
+        format!("{:?}", apikey::Secret::parse(&hash, None).unwrap())
+    );
+    assert!(apikey::Secret::parse("sha256:abc", None).is_err());
+    assert!(apikey::Secret::parse(&format!("sha256:{}", "g".repeat(64)), None).is_err());
+}
+
+#[test]
+fn key_rejections_are_told_apart() {
+    use apikey::{ApiKeyError, Scope};
+    let cases = [
+        (ApiKeyError::Missing, Status::Unauthorized, "missing_key"),
+        (ApiKeyError::Invalid, Status::Forbidden, "invalid_key"),
+        (ApiKeyError::Expired, Status::Forbidden, "expired_key"),
+        (
+            ApiKeyError::Forbidden(Scope::Admin),
+            Status::Forbidden,
+            "insufficient_scope",
+        ),
+        (
+            ApiKeyError::Failure,
+            Status::InternalServerError,
+            "key_config_error",
+        ),
+    ];
+    for (rejection, status, error) in cases {
+        let api_error = rejection.to_api_error();
+        assert_eq!((api_error.status, api_error.error), (status, error));
+        let challenges = api_error
+            .headers
+            .iter()
+            .filter(|(name, _)| *name == "WWW-Authenticate")
+            .count();
+        assert_eq!(challenges, usize::from(status == Status::Unauthorized));
+    }
+}
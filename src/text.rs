@@ -0,0 +1,332 @@
+/*! Wrap and shrink `<text>` to fit a box, which plain svg can't do.
+
+A `<text>` element opts in with a `data-fit-width` attribute:
+
+```xml
+<text x="60" y="120" font-family="Inter" font-size="72"
+      data-fit-width="1080" data-fit-height="300"
+      data-min-font-size="40" data-max-lines="3">A long blog post title</text>
+```
+
+Its words are wrapped into lines no wider than `data-fit-width`. If the lines
+don't fit `data-max-lines` or `data-fit-height`, the font size is reduced by
+whole pixels, as little as needed, down to `data-min-font-size` (the element's
+own size when missing). Both sizes are kept between 0.01 and 10000. Whatever
+still doesn't fit is cut and ends with an ellipsis. `data-line-height`
+is the line spacing as a multiple of the font size, 1.2 by default.
+
+Lines are measured by shaping them with the same fonts the render will use.
+`font-family`, `font-size`, `font-weight` and `font-style` are read from the
+element's attributes or `style`, or those of its ancestors; style sheets are
+not consulted. The element's content is replaced by one `<tspan>` per line.
+*/
+use crate::render::Deadline;
+use crate::template::escape;
+use crate::types::Result;
+
+use std::ops::Range;
+use usvg::fontdb::{self, Database, Family, Query, Stretch, Style, Weight};
+
+const ELLIPSIS: char = '\u{2026}';
+const DEFAULT_FONT_SIZE: f32 = 12.0;
+const DEFAULT_LINE_HEIGHT: f32 = 1.2;
+/// Font sizes are kept to this range, so shrinking one takes a bounded number of steps
+const MIN_FONT_SIZE: f32 = 0.01;
+const MAX_FONT_SIZE: f32 = 10_000.0;
+
+/// Lay out every fitted `<text>` in `svg`, stopping at `deadline`. Returns
+/// `None` when there are none, or the svg can't be parsed, which is left for
+/// the renderer to report.
+pub fn fit(svg: &str, db: &Database, deadline: Deadline) -> Result<Option<String>> {
+    let Ok(doc) = roxmltree::Document::parse(svg) else {
+        return Ok(None);
+    };
+    let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+    let fitted = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("text") && n.has_attribute("data-fit-width"));
+    for node in fitted {
+        let (Some(first), Some(last), Some(text)) = (
+            node.first_child(),
+            node.last_child(),
+            TextBox::from_node(node),
+        ) else {
+            continue;
+        };
+        let lines = text.lay_out(db, deadline)?;
+        edits.push((first.range().start..last.range().end, lines));
+    }
+    if edits.is_empty() {
+        return Ok(None);
+    }
+
+    // splice from the end so earlier ranges stay valid
+    edits.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    let mut out = svg.to_string();
+    for (range, content) in edits {
+        out.replace_range(range, &content);
+    }
+    Ok(Some(out))
+}
+
+/// A `<text>` element's content and the box it must fit
+struct TextBox<'a> {
+    text: String,
+    /// Where every line starts: the element's first x, else 0 as for the element
+    x: &'a str,
+    width: f32,
+    height: Option<f32>,
+    font_size: f32,
+    min_font_size: f32,
+    max_lines: Option<usize>,
+    line_height: f32,
+    families: Vec<String>,
+    weight: Weight,
+    style: Style,
+}
+
+impl<'a> TextBox<'a> {
+    fn from_node(node: roxmltree::Node<'a, '_>) -> Option<Self> {
+        let number = |name| node.attribute(name).and_then(parse_length);
+        let font_size = inherited(node, "font-size")
+            .and_then(|s| parse_length(&s))
+            .unwrap_or(DEFAULT_FONT_SIZE)
+            .clamp(MIN_FONT_SIZE, MAX_FONT_SIZE);
+        let text: String = node
+            .descendants()
+            .filter(|n| n.is_text())
+            .filter_map(|n| n.text())
+            .collect();
+        Some(TextBox {
+            text,
+            // only the first of a list of positions applies to every line
+            x: node
+                .attribute("x")
+                .and_then(|x| x.split([' ', ',']).find(|x| !x.is_empty()))
+                .unwrap_or("0"),
+            width: number("data-fit-width")?,
+            height: number("data-fit-height"),
+            font_size,
+            min_font_size: number("data-min-font-size")
+                .unwrap_or(font_size)
+                .clamp(MIN_FONT_SIZE, font_size),
+            max_lines: node
+                .attribute("data-max-lines")
+                .and_then(|n| n.trim().parse().ok())
+                .filter(|&n| n > 0),
+            line_height: number("data-line-height").unwrap_or(DEFAULT_LINE_HEIGHT),
+            families: inherited(node, "font-family")
+                .map(|f| parse_families(&f))
+                .unwrap_or_default(),
+            weight: inherited(node, "font-weight")
+                .map(|w| parse_weight(&w))
+                .unwrap_or(Weight::NORMAL),
+            style: match inherited(node, "font-style").as_deref() {
+                Some("italic") => Style::Italic,
+                Some("oblique") => Style::Oblique,
+                _ => Style::Normal,
+            },
+        })
+    }
+
+    /// The `<tspan>`s that replace the element's content
+    fn lay_out(&self, db: &Database, deadline: Deadline) -> Result<String> {
+        let measure = Measure::new(db, self);
+        let words: Vec<&str> = self.text.split_whitespace().collect();
+        let wrap_at = |size: f32| {
+            deadline.check()?;
+            Ok::<_, eyre::Report>(wrap(&words, self.width / size, &measure))
+        };
+        // `step` pixels smaller, but no smaller than the minimum
+        let size_at = |step: u32| (self.font_size - step as f32).max(self.min_font_size);
+
+        let mut size = self.font_size;
+        let mut lines = wrap_at(size)?;
+        if !self.fits(&lines, size, &measure) && size > self.min_font_size {
+            let mut fitting = (self.font_size - self.min_font_size).ceil() as u32;
+            size = size_at(fitting);
+            lines = wrap_at(size)?;
+            if self.fits(&lines, size, &measure) {
+                // bisect for the fewest steps that fit, each size taking longer to try than to pick
+                let mut too_big = 0;
+                while fitting - too_big > 1 {
+                    let step = too_big + (fitting - too_big) / 2;
+                    let smaller = wrap_at(size_at(step))?;
+                    if self.fits(&smaller, size_at(step), &measure) {
+                        (fitting, size, lines) = (step, size_at(step), smaller);
+                    } else {
+                        too_big = step;
+                    }
+                }
+            }
+        }
+        if !self.fits(&lines, size, &measure) {
+            lines = self.truncate(lines, size, &measure);
+        }
+
+        let x = escape(self.x);
+        let mut out = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            let dy = if i == 0 { 0.0 } else { size * self.line_height };
+            out.push_str(&format!(
+                "<tspan x=\"{x}\" dy=\"{dy}\" font-size=\"{size}\">{}</tspan>",
+                escape(line)
+            ));
+        }
+        Ok(out)
+    }
+
+    /// How many lines the box has room for at `size`
+    fn line_limit(&self, size: f32) -> usize {
+        let by_height = self
+            .height
+            .map(|h| ((h - size) / (size * self.line_height)).floor().max(0.0) as usize + 1);
+        match (self.max_lines, by_height) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b).unwrap_or(usize::MAX),
+        }
+    }
+
+    fn fits(&self, lines: &[String], size: f32, measure: &Measure) -> bool {
+        lines.len() <= self.line_limit(size)
+            && lines.iter().all(|l| measure.em(l) * size <= self.width)
+    }
+
+    /// Drop the lines that don't fit and end the last one kept with an ellipsis.
+    fn truncate(&self, mut lines: Vec<String>, size: f32, measure: &Measure) -> Vec<String> {
+        let max_em = self.width / size;
+        let limit = self.line_limit(size).max(1);
+        let cut = lines.len() > limit;
+        lines.truncate(limit);
+        let last = lines.len() - 1;
+        for (i, line) in lines.iter_mut().enumerate() {
+            if (i == last && cut) || measure.em(line) > max_em {
+                *line = ellipsize(line, max_em, measure);
+            }
+        }
+        lines
+    }
+}
+
+/// Greedily fill lines of at most `max_em`. A word too long for any line gets one to itself.
+fn wrap(words: &[&str], max_em: f32, measure: &Measure) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    for word in words {
+        if line.is_empty() {
+            line.push_str(word);
+            continue;
+        }
+        let candidate = format!("{line} {word}");
+        if measure.em(&candidate) <= max_em {
+            line = candidate;
+        } else {
+            lines.push(std::mem::replace(&mut line, word.to_string()));
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// The longest prefix of `line` that fits `max_em` with an ellipsis after it
+fn ellipsize(line: &str, max_em: f32, measure: &Measure) -> String {
+    let mut end = line.len();
+    loop {
+        let candidate = format!("{}{ELLIPSIS}", line[..end].trim_end());
+        if end == 0 || measure.em(&candidate) <= max_em {
+            return candidate;
+        }
+        end = line[..end].char_indices().last().map_or(0, |(i, _)| i);
+    }
+}
+
+/// Measures text in the face the renderer will pick for it
+struct Measure<'a> {
+    db: &'a Database,
+    face: Option<fontdb::ID>,
+}
+
+impl<'a> Measure<'a> {
+    fn new(db: &'a Database, text: &TextBox) -> Self {
+        let families: Vec<Family> = text
+            .families
+            .iter()
+            .map(|f| match f.as_str() {
+                "serif" => Family::Serif,
+                "sans-serif" => Family::SansSerif,
+                "monospace" => Family::Monospace,
+                "cursive" => Family::Cursive,
+                "fantasy" => Family::Fantasy,
+                name => Family::Name(name),
+            })
+            .chain([Family::Serif])
+            .collect();
+        let face = db.query(&Query {
+            families: &families,
+            weight: text.weight,
+            stretch: Stretch::Normal,
+            style: text.style,
+        });
+        if face.is_none() {
+            warn!("No font to measure {:?} with, estimating", text.families);
+        }
+        Measure { db, face }
+    }
+
+    /// Advance width of `text` in ems
+    fn em(&self, text: &str) -> f32 {
+        let shaped = self.face.and_then(|id| {
+            self.db.with_face_data(id, |data, index| {
+                let face = rustybuzz::Face::from_slice(data, index)?;
+                let mut buffer = rustybuzz::UnicodeBuffer::new();
+                buffer.push_str(text);
+                let glyphs = rustybuzz::shape(&face, &[], buffer);
+                let advance: i32 = glyphs.glyph_positions().iter().map(|p| p.x_advance).sum();
+                Some(advance as f32 / face.units_per_em() as f32)
+            })?
+        });
+        // without a font, guess at an average glyph width
+        shaped.unwrap_or_else(|| text.chars().count() as f32 * 0.5)
+    }
+}
+
+/// A presentation property from `node` or its nearest ancestor that sets it
+fn inherited(node: roxmltree::Node, name: &str) -> Option<String> {
+    node.ancestors().filter(|n| n.is_element()).find_map(|n| {
+        let from_style = n.attribute("style").and_then(|style| {
+            style.split(';').find_map(|decl| {
+                let (key, value) = decl.split_once(':')?;
+                (key.trim() == name).then(|| value.trim().to_string())
+            })
+        });
+        from_style.or_else(|| n.attribute(name).map(str::to_string))
+    })
+}
+
+fn parse_length(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value.strip_suffix("px").unwrap_or(value);
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|v: &f32| *v > 0.0 && v.is_finite())
+}
+
+fn parse_families(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|f| f.trim().trim_matches(|c| c == '"' || c == '\'').to_string())
+        .filter(|f| !f.is_empty())
+        .collect()
+}
+
+fn parse_weight(value: &str) -> Weight {
+    match value.trim() {
+        "bold" | "bolder" => Weight::BOLD,
+        "lighter" => Weight::LIGHT,
+        other => other.parse().map(Weight).unwrap_or(Weight::NORMAL),
+    }
+}
@@ -0,0 +1,83 @@
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::{json, Json, Value};
+use rocket::Request;
+use std::fmt;
+
+/// An error that is the client's to fix, reported as a JSON body like
+/// `{"error": "unknown_preset", "message": "..."}`.
+///
+/// Can travel through `eyre::Report` and be recovered with [`ApiError::from_report`].
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub error: &'static str,
+    pub message: String,
+
+    /// More fields for the body, like the list of missing variables
+    pub details: Vec<(&'static str, Value)>,
+
+    /// Response headers, like `Retry-After`
+    pub headers: Vec<(&'static str, String)>,
+}
+
+impl ApiError {
+    pub fn new(status: Status, error: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            error,
+            message: message.into(),
+            details: Vec::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Add a field to the JSON body.
+    pub fn with(mut self, key: &'static str, value: Value) -> Self {
+        self.details.push((key, value));
+        self
+    }
+
+    /// Add a response header.
+    pub fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    /// Recover an `ApiError` raised during a render, or log the report and treat it as internal.
+    pub fn from_report(report: eyre::Report) -> Self {
+        match report.downcast::<ApiError>() {
+            Ok(api_error) => api_error,
+            Err(report) => {
+                error!("Error while rendering: {report:?}");
+                ApiError::new(
+                    Status::InternalServerError,
+                    "internal_error",
+                    "failed to render",
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut body = json!({"error": self.error, "message": self.message});
+        for (key, value) in self.details {
+            body[key] = value;
+        }
+        let mut response = (self.status, Json(body)).respond_to(req)?;
+        for (name, value) in self.headers {
+            response.set_header(Header::new(name, value));
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,225 @@
+use rocket::http::Status;
+use rocket::serde::json;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+use tokio::fs;
+
+use super::{resource_name, ApiError, Result, Store, SvgId, SvgInfo, SvgPackage};
+
+const SVG_FILE: &str = "main.svg";
+const INFO_FILE: &str = "info.json";
+const RESOURCE_DIR: &str = "resources";
+
+/// A [`Store`] keeping each svg in its own directory:
+/// `<root>/<id>/main.svg` with its resources under `<root>/<id>/resources/`,
+/// and its [`SvgInfo`] in `<root>/<id>/info.json`.
+pub struct FileStore {
+    root: PathBuf,
+    /// Held while an svg's directory is replaced or removed, so other writers
+    /// wait their turn and readers that found it missing can wait and look again
+    locks: Mutex<HashMap<SvgId, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl FileStore {
+    pub async fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        fs::create_dir_all(&root).await?;
+        Ok(FileStore {
+            root: fs::canonicalize(root).await?,
+            locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn dir(&self, id: &SvgId) -> PathBuf {
+        self.root.join(id.as_ref())
+    }
+
+    /// The lock for `id`'s directory, dropping those nobody holds.
+    fn lock(&self, id: &SvgId) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self
+            .locks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(id.clone()).or_default().clone()
+    }
+
+    /// Run `read`, and if it found nothing, again once any update to `id` is done,
+    /// as its directory is briefly missing while being replaced.
+    async fn settled<T, F>(&self, id: &SvgId, read: impl Fn() -> F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let result = match read().await {
+            Err(err) if not_found(&err) => {
+                let lock = self.lock(id);
+                let _settled = lock.lock().await;
+                read().await
+            }
+            result => result,
+        };
+        result.map_err(|err| {
+            if not_found(&err) {
+                unknown(id).into()
+            } else {
+                err
+            }
+        })
+    }
+
+    /// Build `package` in a directory aside from the store, to be moved in place
+    /// so a partial write is never loaded.
+    async fn stage(
+        &self,
+        id: &SvgId,
+        package: &SvgPackage,
+        created: OffsetDateTime,
+    ) -> Result<PathBuf> {
+        let staged = self.root.join(format!(".{id}.{}.partial", SvgId::new()));
+        let info = SvgInfo::new(id.clone(), package, created);
+        if let Err(err) = FileStore::write(&staged, package, &info).await {
+            let _ = fs::remove_dir_all(&staged).await;
+            return Err(err);
+        }
+        Ok(staged)
+    }
+
+    /// Move `staged` into `id`'s place, putting the old directory back if that fails.
+    async fn replace(&self, id: &SvgId, staged: &Path) -> Result<()> {
+        let dir = self.dir(id);
+        let old = self.root.join(format!(".{id}.{}.old", SvgId::new()));
+        fs::rename(&dir, &old).await?;
+        if let Err(err) = fs::rename(staged, &dir).await {
+            fs::rename(&old, &dir).await?;
+            return Err(err.into());
+        }
+        if let Err(err) = fs::remove_dir_all(&old).await {
+            warn!("Failed to remove replaced svg {old:?}: {err}");
+        }
+        Ok(())
+    }
+
+    async fn read_info(&self, id: &SvgId) -> Result<SvgInfo> {
+        Ok(json::from_slice(
+            &fs::read(self.dir(id).join(INFO_FILE)).await?,
+        )?)
+    }
+
+    async fn read_package(&self, id: &SvgId) -> Result<SvgPackage> {
+        let dir = self.dir(id);
+        let svg = fs::read(dir.join(SVG_FILE)).await?;
+
+        let mut resources = HashMap::new();
+        let resource_dir = dir.join(RESOURCE_DIR);
+        let mut pending = vec![resource_dir.clone()];
+        while let Some(next) = pending.pop() {
+            let mut entries = fs::read_dir(&next).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let name = path
+                    .strip_prefix(&resource_dir)?
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                resources.insert(name, fs::read(&path).await?);
+            }
+        }
+        Ok(SvgPackage { svg, resources })
+    }
+
+    async fn write(dir: &Path, package: &SvgPackage, info: &SvgInfo) -> Result<()> {
+        fs::create_dir_all(dir.join(RESOURCE_DIR)).await?;
+        fs::write(dir.join(INFO_FILE), json::to_string(info)?).await?;
+        fs::write(dir.join(SVG_FILE), &package.svg).await?;
+        for (name, data) in &package.resources {
+            let path = dir.join(RESOURCE_DIR).join(resource_name(name)?);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::write(path, data).await?;
+        }
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Store for FileStore {
+    async fn save(&self, package: &SvgPackage) -> Result<SvgId> {
+        let id = SvgId::new();
+        let staged = self.stage(&id, package, OffsetDateTime::now_utc()).await?;
+        fs::rename(&staged, self.dir(&id)).await?;
+        info!("Stored svg {id}");
+        Ok(id)
+    }
+
+    async fn update(&self, file_id: &SvgId, package: &SvgPackage) -> Result<()> {
+        let lock = self.lock(file_id);
+        let _updating = lock.lock().await;
+        let created = match self.read_info(file_id).await {
+            Err(err) if not_found(&err) => return Err(unknown(file_id).into()),
+            info => info?.created,
+        };
+        let staged = self.stage(file_id, package, created).await?;
+        if let Err(err) = self.replace(file_id, &staged).await {
+            let _ = fs::remove_dir_all(&staged).await;
+            return Err(err);
+        }
+        info!("Updated svg {file_id}");
+        Ok(())
+    }
+
+    async fn load(&self, file_id: &SvgId) -> Result<SvgPackage> {
+        self.settled(file_id, || self.read_package(file_id)).await
+    }
+
+    async fn info(&self, file_id: &SvgId) -> Result<SvgInfo> {
+        self.settled(file_id, || self.read_info(file_id)).await
+    }
+
+    async fn list(&self) -> Result<Vec<SvgInfo>> {
+        let mut infos = Vec::new();
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            // skips saves in progress, which are hidden
+            let Some(id) = entry.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            match self.info(&id).await {
+                Ok(info) => infos.push(info),
+                Err(err) => warn!("Skipping unreadable svg {id}: {err}"),
+            }
+        }
+        infos.sort_by_key(|info| info.created);
+        Ok(infos)
+    }
+
+    async fn delete(&self, file_id: &SvgId) -> Result<()> {
+        let lock = self.lock(file_id);
+        let _deleting = lock.lock().await;
+        match fs::remove_dir_all(self.dir(file_id)).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => Err(unknown(file_id).into()),
+            result => {
+                result?;
+                info!("Deleted svg {file_id}");
+                Ok(())
+            }
+        }
+    }
+}
+
+fn not_found(err: &color_eyre::Report) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == ErrorKind::NotFound)
+}
+
+fn unknown(id: &SvgId) -> ApiError {
+    ApiError::new(Status::NotFound, "not_found", format!("no svg {id}"))
+}
@@ -0,0 +1,110 @@
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use time::format_description::FormatItem;
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+
+use super::Rendered;
+
+/// The IMF-fixdate form of an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+const HTTP_DATE: &[FormatItem<'static>] = time::macros::format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
+
+fn http_date(date: OffsetDateTime) -> String {
+    date.to_offset(time::UtcOffset::UTC)
+        .format(HTTP_DATE)
+        .expect("any date formats")
+}
+
+/// Describes a response so clients and CDNs can keep and revalidate it
+#[derive(Clone, Debug)]
+pub struct Validators {
+    /// Strong entity tag, without quotes
+    pub etag: String,
+
+    /// When the source last changed, if known
+    pub last_modified: Option<OffsetDateTime>,
+
+    /// How long the response may be reused without revalidating
+    pub max_age: u64,
+
+    /// Made with an api key, so only the client that asked may keep it, not shared caches
+    pub private: bool,
+}
+
+/// The conditional headers a client sent to revalidate its copy
+pub struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<OffsetDateTime>,
+}
+
+impl Conditions {
+    /// True if the client's copy is still current, so `304 Not Modified` will do.
+    pub fn not_modified(&self, validators: &Validators) -> bool {
+        // If-None-Match wins when both are sent
+        if let Some(tags) = &self.if_none_match {
+            return tags.split(',').map(str::trim).any(|tag| {
+                tag == "*" || tag.trim_start_matches("W/").trim_matches('"') == validators.etag
+            });
+        }
+        match (self.if_modified_since, validators.last_modified) {
+            // http dates have no fraction of a second
+            (Some(since), Some(modified)) => modified.replace_nanosecond(0).ok() <= Some(since),
+            _ => false,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Conditions {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        Outcome::Success(Conditions {
+            if_none_match: headers.get_one("If-None-Match").map(str::to_string),
+            if_modified_since: headers
+                .get_one("If-Modified-Since")
+                .and_then(|date| PrimitiveDateTime::parse(date, HTTP_DATE).ok())
+                .map(PrimitiveDateTime::assume_utc),
+        })
+    }
+}
+
+/// A render with caching headers, or just the headers when the client's copy is current
+pub struct Cacheable {
+    pub validators: Validators,
+
+    /// `None` answers `304 Not Modified`
+    pub rendered: Option<Rendered>,
+}
+
+impl<'r> Responder<'r, 'static> for Cacheable {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = match self.rendered {
+            Some(rendered) => rendered.respond_to(req)?,
+            None => Response::build().status(Status::NotModified).finalize(),
+        };
+        let validators = self.validators;
+        let max_age = validators.max_age.min(i64::MAX as u64) as i64;
+        response.set_header(Header::new("ETag", format!("\"{}\"", validators.etag)));
+        let scope = if validators.private {
+            "private"
+        } else {
+            "public"
+        };
+        response.set_header(Header::new(
+            "Cache-Control",
+            format!("{scope}, max-age={max_age}"),
+        ));
+        response.set_header(Header::new(
+            "Expires",
+            http_date(OffsetDateTime::now_utc().saturating_add(Duration::seconds(max_age))),
+        ));
+        if let Some(modified) = validators.last_modified {
+            response.set_header(Header::new("Last-Modified", http_date(modified)));
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,180 @@
+use rocket::form::{Errors, Form};
+use rocket::http::Status;
+use rocket::serde::json::{self, json, Json};
+use std::io;
+use tiny_skia::IntSize;
+
+use super::ApiError;
+
+/// Bounds on what one request may upload and render, so a hostile svg can't
+/// exhaust memory. Each is checked before the memory it guards is allocated.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Largest whole request body, enforced by rocket while reading it
+    pub max_request_bytes: u64,
+    pub max_resource_bytes: u64,
+    pub max_resources: usize,
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Largest `width * height` of a raster output
+    pub max_pixels: u64,
+    /// Most XML nodes an svg may have, after its variables are filled
+    pub max_svg_nodes: u32,
+}
+
+impl Limits {
+    /// Check the number of uploaded resources.
+    pub fn check_resource_count(&self, count: usize) -> Result<(), ApiError> {
+        if count > self.max_resources {
+            return Err(exceeded(
+                Status::PayloadTooLarge,
+                "too_many_resources",
+                format!("{count} resources is more than {}", self.max_resources),
+                "max_resources",
+                self.max_resources as u64,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check one uploaded resource's size, before reading it.
+    pub fn check_resource(&self, name: &str, bytes: u64) -> Result<(), ApiError> {
+        if bytes > self.max_resource_bytes {
+            return Err(exceeded(
+                Status::PayloadTooLarge,
+                "resource_too_large",
+                format!(
+                    "resource {name} is {bytes} bytes, more than {}",
+                    self.max_resource_bytes
+                ),
+                "max_resource_bytes",
+                self.max_resource_bytes,
+            )
+            .with("resource", json!(name)));
+        }
+        Ok(())
+    }
+
+    /// Check a raster output's dimensions, before allocating its pixmap.
+    pub fn check_output(&self, size: IntSize) -> Result<(), ApiError> {
+        let (width, height) = (size.width(), size.height());
+        let (limit, max) = if width > self.max_width {
+            ("max_width", self.max_width as u64)
+        } else if height > self.max_height {
+            ("max_height", self.max_height as u64)
+        } else if width as u64 * height as u64 > self.max_pixels {
+            ("max_pixels", self.max_pixels)
+        } else {
+            return Ok(());
+        };
+        Err(exceeded(
+            Status::UnprocessableEntity,
+            "output_too_large",
+            format!("a {width}x{height} output is over {limit} of {max}"),
+            limit,
+            max,
+        )
+        .with("width", json!(width))
+        .with("height", json!(height)))
+    }
+
+    /// The error for an svg with more than `max_svg_nodes` nodes
+    pub fn too_many_nodes(&self) -> ApiError {
+        exceeded(
+            Status::UnprocessableEntity,
+            "svg_too_complex",
+            format!("the svg has more than {} nodes", self.max_svg_nodes),
+            "max_svg_nodes",
+            self.max_svg_nodes as u64,
+        )
+    }
+
+    /// The error for a compressed svg that expands past `max_request_bytes`,
+    /// which it couldn't have been uploaded as uncompressed
+    pub fn svg_too_large(&self) -> ApiError {
+        exceeded(
+            Status::PayloadTooLarge,
+            "svg_too_large",
+            format!(
+                "the decompressed svg is more than {} bytes",
+                self.max_request_bytes
+            ),
+            "max_request_bytes",
+            self.max_request_bytes,
+        )
+    }
+
+    /// A parsed form, or why it couldn't be parsed. Rocket reports a body cut off
+    /// at `max_request_bytes` alongside the fields that went missing, so that is
+    /// looked for first.
+    pub fn form<'r, T>(&self, form: Result<Form<T>, Errors<'r>>) -> Result<Form<T>, ApiError> {
+        let errors = match form {
+            Ok(form) => return Ok(form),
+            Err(errors) => errors,
+        };
+        if errors.iter().any(|e| e.status() == Status::PayloadTooLarge) {
+            return Err(self.request_too_large());
+        }
+        let messages: Vec<String> = errors
+            .iter()
+            .map(|e| match &e.name {
+                Some(name) => format!("{name}: {e}"),
+                None => e.to_string(),
+            })
+            .collect();
+        Err(
+            ApiError::new(errors.status(), "invalid_form", messages.join(", "))
+                .with("errors", json!(messages)),
+        )
+    }
+
+    /// A parsed JSON body, or why it couldn't be parsed: too large, malformed,
+    /// or not the shape expected.
+    pub fn json<'r, T>(&self, body: Result<Json<T>, json::Error<'r>>) -> Result<Json<T>, ApiError> {
+        match body {
+            Ok(body) => Ok(body),
+            Err(json::Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(self.request_too_large())
+            }
+            Err(json::Error::Io(err)) => Err(ApiError::new(
+                Status::BadRequest,
+                "invalid_json",
+                err.to_string(),
+            )),
+            Err(json::Error::Parse(_, err)) => {
+                let status = match err.classify() {
+                    json::serde_json::error::Category::Data => Status::UnprocessableEntity,
+                    _ => Status::BadRequest,
+                };
+                Err(ApiError::new(status, "invalid_json", err.to_string()))
+            }
+        }
+    }
+
+    /// The error for a request body over `max_request_bytes`
+    pub fn request_too_large(&self) -> ApiError {
+        exceeded(
+            Status::PayloadTooLarge,
+            "request_too_large",
+            format!(
+                "the request body is more than {} bytes",
+                self.max_request_bytes
+            ),
+            "max_request_bytes",
+            self.max_request_bytes,
+        )
+    }
+}
+
+/// A limit error naming the setting that was hit and its value
+fn exceeded(
+    status: Status,
+    error: &'static str,
+    message: String,
+    limit: &'static str,
+    max: u64,
+) -> ApiError {
+    ApiError::new(status, error, message)
+        .with("limit", json!(limit))
+        .with("max", json!(max))
+}
@@ -1,7 +1,35 @@
+mod api_error;
+mod file_store;
+mod http_cache;
+mod limits;
+mod output_format;
+mod render_request;
 mod render_space;
+mod rendered;
+mod sizing;
+mod store;
 mod svg_description;
+mod svg_id;
+mod svg_json;
+mod svg_package;
+mod svg_upload;
+mod variant;
 
+pub use api_error::ApiError;
+pub use file_store::FileStore;
+pub use http_cache::{Cacheable, Conditions, Validators};
+pub use limits::Limits;
+pub use output_format::{ArchiveFormat, Background, Encoding, OutputFormat};
+pub use render_request::RenderRequest;
 pub use render_space::RenderSpace;
+pub use rendered::Rendered;
+pub use sizing::{FitMode, Sizing};
+pub use store::Store;
 pub use svg_description::SvgDescription;
+pub use svg_id::SvgId;
+pub use svg_json::SvgJson;
+pub use svg_package::{resource_name, SvgInfo, SvgPackage};
+pub use svg_upload::SvgUpload;
+pub use variant::{Target, Variant};
 
 pub type Result<T> = color_eyre::Result<T>;
@@ -0,0 +1,225 @@
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::http::{Accept, ContentType, MediaType};
+use rocket::serde::{de, Deserialize, Deserializer, Serialize};
+use std::str::FromStr;
+
+/// The formats a render can be encoded to. All but PDF are rasterized.
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[field(value = "png")]
+    Png,
+    #[field(value = "jpeg")]
+    #[field(value = "jpg")]
+    #[serde(alias = "jpg")]
+    Jpeg,
+    #[field(value = "webp")]
+    WebP,
+    #[field(value = "pdf")]
+    Pdf,
+}
+
+impl OutputFormat {
+    pub fn content_type(self) -> ContentType {
+        match self {
+            OutputFormat::Png => ContentType::PNG,
+            OutputFormat::Jpeg => ContentType::JPEG,
+            OutputFormat::WebP => ContentType::WEBP,
+            OutputFormat::Pdf => ContentType::PDF,
+        }
+    }
+
+    /// true if dropping quality makes the file smaller
+    pub fn is_lossy(self, lossless: bool) -> bool {
+        match self {
+            OutputFormat::Jpeg => true,
+            OutputFormat::WebP => !lossless,
+            OutputFormat::Png | OutputFormat::Pdf => false,
+        }
+    }
+
+    /// true if this format keeps the svg as vectors instead of rendering to pixels
+    pub fn is_vector(self) -> bool {
+        self == OutputFormat::Pdf
+    }
+
+    /// File name extension
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Pdf => "pdf",
+        }
+    }
+
+    /// The format a file name extension like `png` or `jpeg` stands for
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::WebP),
+            "pdf" => Some(OutputFormat::Pdf),
+            _ => None,
+        }
+    }
+
+    /// The format for a concrete media type like `image/webp`. Wildcards map to PNG.
+    fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        match (media_type.top().as_str(), media_type.sub().as_str()) {
+            ("*", "*") | ("image", "*") => Some(OutputFormat::Png),
+            ("image", "png") => Some(OutputFormat::Png),
+            ("image", "jpeg") | ("image", "jpg") => Some(OutputFormat::Jpeg),
+            ("image", "webp") => Some(OutputFormat::WebP),
+            ("application", "pdf") => Some(OutputFormat::Pdf),
+            _ => None,
+        }
+    }
+
+    /// Pick the format the client most prefers. PNG when no `Accept` was sent,
+    /// `None` when nothing it accepts can be produced.
+    pub fn negotiate(accept: Option<&Accept>) -> Option<Self> {
+        negotiate(accept, OutputFormat::Png, Self::from_media_type)
+    }
+}
+
+/// How several variants are returned together
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    #[field(value = "zip")]
+    Zip,
+    /// `multipart/mixed`, one part per variant
+    #[field(value = "multipart")]
+    Multipart,
+}
+
+impl ArchiveFormat {
+    fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        match (media_type.top().as_str(), media_type.sub().as_str()) {
+            ("*", "*") | ("application", "zip") => Some(ArchiveFormat::Zip),
+            ("multipart", "mixed") => Some(ArchiveFormat::Multipart),
+            _ => None,
+        }
+    }
+
+    /// Pick the archive the client most prefers. ZIP when no `Accept` was sent,
+    /// `None` when nothing it accepts can be produced.
+    pub fn negotiate(accept: Option<&Accept>) -> Option<Self> {
+        negotiate(accept, ArchiveFormat::Zip, Self::from_media_type)
+    }
+}
+
+/// The first of the client's acceptable media types, by weight, that `from_media_type` supports.
+fn negotiate<T>(
+    accept: Option<&Accept>,
+    default: T,
+    from_media_type: impl Fn(&MediaType) -> Option<T>,
+) -> Option<T> {
+    let accept = match accept {
+        None => return Some(default),
+        Some(accept) => accept,
+    };
+    let mut choices: Vec<_> = accept.iter().filter(|q| q.weight_or(1.0) > 0.0).collect();
+    // stable, so equally weighted types keep the client's order
+    choices.sort_by(|a, b| b.weight_or(1.0).total_cmp(&a.weight_or(1.0)));
+    choices
+        .into_iter()
+        .find_map(|q| from_media_type(q.media_type()))
+}
+
+/// Colour painted behind the render, e.g. `white` or `#336699`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Background(pub tiny_skia::Color);
+
+impl Background {
+    /// The colour as it looks over white, for formats that can't be transparent.
+    pub fn opaque(self) -> Self {
+        let c = self.0;
+        let over_white = |channel: f32| channel * c.alpha() + (1.0 - c.alpha());
+        Background(
+            tiny_skia::Color::from_rgba(
+                over_white(c.red()),
+                over_white(c.green()),
+                over_white(c.blue()),
+                1.0,
+            )
+            .unwrap_or(tiny_skia::Color::WHITE),
+        )
+    }
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background(tiny_skia::Color::WHITE)
+    }
+}
+
+impl FromStr for Background {
+    type Err = svgtypes::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let c = svgtypes::Color::from_str(s)?;
+        Ok(Background(tiny_skia::Color::from_rgba8(
+            c.red, c.green, c.blue, c.alpha,
+        )))
+    }
+}
+
+impl<'de> Deserialize<'de> for Background {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let color = String::deserialize(deserializer)?;
+        color
+            .parse()
+            .map_err(|_| de::Error::custom(format!("{color:?} is not a css color")))
+    }
+}
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for Background {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        field
+            .value
+            .parse()
+            .map_err(|_| form::Error::validation("not a css color").into())
+    }
+}
+
+/// How the rendered pixels are turned into bytes
+#[derive(Clone, Copy, Debug)]
+pub struct Encoding {
+    pub format: OutputFormat,
+
+    /// 1-100, used by JPEG and lossy WebP
+    pub quality: u8,
+
+    /// Use lossless WebP
+    pub lossless: bool,
+
+    /// Painted behind the image before encoding. Formats without alpha
+    /// (JPEG) are always flattened, using white if this is unset and
+    /// showing white through any transparency in it.
+    pub background: Option<Background>,
+
+    /// Largest acceptable output. Lossy formats drop quality to fit.
+    pub max_bytes: Option<usize>,
+}
+
+impl Encoding {
+    pub const DEFAULT_QUALITY: u8 = 85;
+
+    /// Lossy formats won't go below this quality to meet `max_bytes`.
+    pub const MIN_QUALITY: u8 = 40;
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding {
+            format: OutputFormat::Png,
+            quality: Self::DEFAULT_QUALITY,
+            lossless: false,
+            background: None,
+            max_bytes: None,
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use std::collections::HashMap;
+
+use super::{ArchiveFormat, SvgPackage, Variant};
+
+/// Everything a `POST /image` asks for, however it was sent
+pub struct RenderRequest {
+    pub package: SvgPackage,
+
+    /// Values for the svg's `{{name}}` placeholders
+    pub vars: HashMap<String, String>,
+
+    /// The top level output fields, which variants fall back to
+    pub defaults: Variant,
+
+    /// Several outputs to return together. Only `defaults` is rendered when empty.
+    pub variants: Vec<Variant>,
+
+    /// How variants are packaged, negotiated when missing
+    pub archive: Option<ArchiveFormat>,
+}
@@ -0,0 +1,31 @@
+use crate::fonts::FontResolution;
+use rocket::http::{ContentType, Header};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use std::io::Cursor;
+
+/// The result of a render, along with what was learned while producing it
+pub struct Rendered {
+    pub content_type: ContentType,
+
+    /// Encoded image bytes
+    pub data: Vec<u8>,
+
+    /// How each `font-family` in the svg was resolved. Sent back as one
+    /// `X-Font-Resolution` header per entry.
+    pub fonts: Vec<FontResolution>,
+}
+
+impl<'r> Responder<'r, 'static> for Rendered {
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .header(self.content_type)
+            .raw_header("Vary", "Accept")
+            .sized_body(self.data.len(), Cursor::new(self.data));
+        for font in &self.fonts {
+            response.header_adjoin(Header::new("X-Font-Resolution", font.to_header_value()));
+        }
+        response.ok()
+    }
+}
@@ -0,0 +1,85 @@
+use rocket::serde::{Deserialize, Serialize};
+use tiny_skia::{IntSize, Transform};
+use usvg::Size;
+
+/// How the svg is placed when both a width and a height are requested
+#[derive(FromFormField, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum FitMode {
+    /// Scale uniformly to fit inside the box, letterboxing the rest
+    #[default]
+    #[field(value = "contain")]
+    Contain,
+    /// Scale uniformly to fill the box, cropping what overflows
+    #[field(value = "cover")]
+    Cover,
+    /// Scale each axis independently to fill the box exactly
+    #[field(value = "stretch")]
+    Stretch,
+    /// Keep the svg at its own size, centered in the box and cropped or padded
+    #[field(value = "exact")]
+    Exact,
+}
+
+/// Requested output dimensions. With nothing set the svg's own size is used.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sizing {
+    /// Target width in pixels, before zoom
+    pub width: Option<u32>,
+
+    /// Target height in pixels, before zoom
+    pub height: Option<u32>,
+
+    /// Multiplies the final size, e.g. 2 for a 2x variant
+    pub zoom: Option<f32>,
+
+    pub fit: FitMode,
+}
+
+impl Sizing {
+    /// The pixmap size for an svg of `size`, and the transform that places it there.
+    pub fn layout(&self, size: Size) -> Option<(IntSize, Transform)> {
+        let (w0, h0) = (size.width(), size.height());
+        let zoom = self.zoom.unwrap_or(1.0);
+
+        let (box_w, box_h, sx, sy) = match (self.width, self.height) {
+            (None, None) => (w0, h0, 1.0, 1.0),
+            (Some(w), None) => {
+                let s = w as f32 / w0;
+                (w as f32, (h0 * s).round(), s, s)
+            }
+            (None, Some(h)) => {
+                let s = h as f32 / h0;
+                ((w0 * s).round(), h as f32, s, s)
+            }
+            (Some(w), Some(h)) => {
+                let (w, h) = (w as f32, h as f32);
+                let (sx, sy) = match self.fit {
+                    FitMode::Contain => {
+                        let s = (w / w0).min(h / h0);
+                        (s, s)
+                    }
+                    FitMode::Cover => {
+                        let s = (w / w0).max(h / h0);
+                        (s, s)
+                    }
+                    FitMode::Stretch => (w / w0, h / h0),
+                    FitMode::Exact => (1.0, 1.0),
+                };
+                (w, h, sx, sy)
+            }
+        };
+
+        let pixmap_size = IntSize::from_wh(
+            (box_w * zoom).ceil().max(1.0) as u32,
+            (box_h * zoom).ceil().max(1.0) as u32,
+        )?;
+        // center whatever doesn't fill the box
+        let dx = (box_w - w0 * sx) / 2.0;
+        let dy = (box_h - h0 * sy) / 2.0;
+        let transform = Transform::from_scale(zoom, zoom)
+            .pre_translate(dx, dy)
+            .pre_scale(sx, sy);
+        Some((pixmap_size, transform))
+    }
+}
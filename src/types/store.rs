@@ -1,17 +1,23 @@
+use super::{Result, SvgId, SvgInfo, SvgPackage};
 
-use super::{SvgId, SvgDescription, Result};
+/// keep svg elements in some sort of persistent storage
+#[rocket::async_trait]
+pub trait Store: Sync + Send {
+    /// save an svg and its resources to the store under a new id
+    async fn save(&self, package: &SvgPackage) -> Result<SvgId>;
 
-/// keep svg elementsin some sort of persistent storage
-#[async_trait]
-pub trait Store : Sync + Send {
-    /// save some bytes with a certain mime type to the store
-    async fn save(&mut self, package: SvgDescription) -> Result<SvgId>;
+    /// replace the svg and resources kept under an existing id
+    async fn update(&self, file_id: &SvgId, package: &SvgPackage) -> Result<()>;
 
-    /// retreive bytes from the store
-    async fn load(&self, file_id: SvgId) -> Result<SvgDescription>;
+    /// retrieve an svg and its resources from the store
+    async fn load(&self, file_id: &SvgId) -> Result<SvgPackage>;
 
-    /// Delete a given fileid from store
-    async fn delete(&mut self, file_id: SvgId) -> Result<()>;
-}
+    /// describe a stored svg without loading it
+    async fn info(&self, file_id: &SvgId) -> Result<SvgInfo>;
 
+    /// describe every stored svg
+    async fn list(&self) -> Result<Vec<SvgInfo>>;
 
+    /// Delete a given fileid from store
+    async fn delete(&self, file_id: &SvgId) -> Result<()>;
+}
@@ -1,3 +1,12 @@
+use super::svg_upload;
+use super::variant::{
+    valid_quality, valid_size, valid_zoom, HEIGHT_POSITIVE, QUALITY_RANGE, WIDTH_POSITIVE,
+    ZOOM_POSITIVE,
+};
+use super::{
+    ArchiveFormat, Background, FitMode, Limits, OutputFormat, RenderRequest, Result, SvgPackage,
+    Variant,
+};
 use rocket::fs::TempFile;
 use std::collections::HashMap;
 
@@ -10,4 +19,81 @@ pub struct SvgDescription<'a> {
     /// Resources are files that will be referred to during render.
     /// if a ttf, ttc, otc, or otf is provided it will be loaded for use.
     pub resources: HashMap<String, TempFile<'a>>,
+
+    /// Values for `{{name}}` placeholders in the svg, sent as `vars[name]`.
+    pub vars: HashMap<String, String>,
+
+    /// Name of a platform preset supplying size, fit, format and file size limit.
+    /// Any other field that is sent takes precedence over the preset, and a
+    /// width or height replaces its size entirely.
+    pub preset: Option<String>,
+
+    /// Output format. When missing, the preset's or else negotiated from the `Accept` header.
+    pub format: Option<OutputFormat>,
+
+    /// Quality for JPEG and lossy WebP, 1-100.
+    #[field(validate = with(valid_quality, QUALITY_RANGE))]
+    pub quality: Option<u8>,
+
+    /// Encode WebP losslessly.
+    pub lossless: Option<bool>,
+
+    /// Flatten the image onto this css color. Also fills any letterboxing.
+    pub background: Option<Background>,
+
+    /// Output width in pixels. The svg's own size when missing.
+    #[field(validate = with(valid_size, WIDTH_POSITIVE))]
+    pub width: Option<u32>,
+
+    /// Output height in pixels. The svg's own size when missing.
+    #[field(validate = with(valid_size, HEIGHT_POSITIVE))]
+    pub height: Option<u32>,
+
+    /// Scale factor applied after width and height, e.g. 2 for a 2x image.
+    #[field(validate = with(valid_zoom, ZOOM_POSITIVE))]
+    pub zoom: Option<f32>,
+
+    /// How the svg fills the box when both width and height are given.
+    pub fit: Option<FitMode>,
+
+    /// Several outputs to produce from this one svg, returned together as an
+    /// archive. The fields above are defaults for every variant.
+    pub variants: Vec<Variant>,
+
+    /// How variants are packaged. When missing, negotiated from the `Accept` header.
+    pub archive: Option<ArchiveFormat>,
+}
+
+impl SvgDescription<'_> {
+    /// Read the svg and resources into memory.
+    pub async fn package(&self, limits: &Limits) -> Result<SvgPackage> {
+        svg_upload::package(&self.svg, &self.resources, limits).await
+    }
+
+    /// Read the upload into a [`RenderRequest`], taking its vars and variants.
+    pub async fn render_request(&mut self, limits: &Limits) -> Result<RenderRequest> {
+        Ok(RenderRequest {
+            package: self.package(limits).await?,
+            vars: std::mem::take(&mut self.vars),
+            defaults: self.defaults(),
+            variants: std::mem::take(&mut self.variants),
+            archive: self.archive,
+        })
+    }
+
+    /// The top level output fields, which variants fall back to.
+    pub fn defaults(&self) -> Variant {
+        Variant {
+            name: None,
+            preset: self.preset.clone(),
+            format: self.format,
+            quality: self.quality,
+            lossless: self.lossless,
+            background: self.background,
+            width: self.width,
+            height: self.height,
+            zoom: self.zoom,
+            fit: self.fit,
+        }
+    }
 }
@@ -0,0 +1,70 @@
+use rand::{self, Rng};
+use rocket::serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Identifies an svg kept in a [`Store`](super::Store). Always base58, so it
+/// is safe to use as a file name or in a url.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub struct SvgId(String);
+
+impl SvgId {
+    /// A new random id
+    pub fn new() -> Self {
+        let bytes = rand::thread_rng().gen::<[u8; 16]>();
+        SvgId(bs58::encode(bytes).into_string())
+    }
+}
+
+impl Default for SvgId {
+    fn default() -> Self {
+        SvgId::new()
+    }
+}
+
+/// The string is not a valid id
+#[derive(Debug)]
+pub struct InvalidSvgId;
+
+impl FromStr for SvgId {
+    type Err = InvalidSvgId;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match bs58::decode(s).into_vec() {
+            Ok(bytes) if !bytes.is_empty() && bytes.len() <= 32 => Ok(SvgId(s.to_string())),
+            _ => Err(InvalidSvgId),
+        }
+    }
+}
+
+impl fmt::Display for InvalidSvgId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("not a valid svg id")
+    }
+}
+
+impl TryFrom<String> for SvgId {
+    type Error = InvalidSvgId;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<SvgId> for String {
+    fn from(id: SvgId) -> String {
+        id.0
+    }
+}
+
+impl fmt::Display for SvgId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for SvgId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
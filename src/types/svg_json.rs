@@ -0,0 +1,107 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rocket::http::Status;
+use rocket::serde::json::json;
+use rocket::serde::Deserialize;
+use std::collections::HashMap;
+
+use super::{
+    resource_name, ApiError, ArchiveFormat, Limits, RenderRequest, Result, Store, SvgId,
+    SvgPackage, Variant,
+};
+
+/// What to render, sent to `POST /image` as JSON instead of a multipart form
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SvgJson {
+    /// Raw svg content
+    pub svg: String,
+
+    /// Files the svg refers to, by the name it uses for them
+    #[serde(default)]
+    pub resources: HashMap<String, JsonResource>,
+
+    /// Values for `{{name}}` placeholders in the svg
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+
+    /// `preset`, `format`, `width` and the other output fields, at the top level
+    #[serde(flatten)]
+    pub output: Variant,
+
+    #[serde(default)]
+    pub variants: Vec<Variant>,
+
+    pub archive: Option<ArchiveFormat>,
+}
+
+/// Where a resource's content comes from
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum JsonResource {
+    /// `{"base64": "iVBORw0KGgo..."}`
+    Base64(String),
+
+    /// `{"template": {"id": "...", "name": "img/logo.png"}}`, a resource of a
+    /// stored template. `name` is the resource's own name when missing.
+    Template { id: SvgId, name: Option<String> },
+}
+
+impl SvgJson {
+    /// Decode and fetch the resources into a [`RenderRequest`], checking them against `limits`.
+    pub async fn render_request(self, store: &dyn Store, limits: &Limits) -> Result<RenderRequest> {
+        for variant in std::iter::once(&self.output).chain(&self.variants) {
+            variant.validate()?;
+        }
+        limits.check_resource_count(self.resources.len())?;
+
+        // each template is loaded once, however many of its resources are used
+        let mut templates: HashMap<SvgId, SvgPackage> = HashMap::new();
+        let mut resources = HashMap::with_capacity(self.resources.len());
+        for (name, source) in self.resources {
+            let name = resource_name(&name)?;
+            let data = match source {
+                JsonResource::Base64(data) => {
+                    // padding aside, every 4 characters decode to 3 bytes
+                    let decoded = (data.len() as u64 / 4 * 3).saturating_sub(2);
+                    limits.check_resource(&name, decoded)?;
+                    BASE64
+                        .decode(data.trim())
+                        .map_err(|err| invalid_resource(&name, format!("not base64: {err}")))?
+                }
+                JsonResource::Template { id, name: stored } => {
+                    if !templates.contains_key(&id) {
+                        let package = store.load(&id).await?;
+                        templates.insert(id.clone(), package);
+                    }
+                    let stored = resource_name(stored.as_deref().unwrap_or(&name))?;
+                    templates[&id]
+                        .resources
+                        .get(&stored)
+                        .cloned()
+                        .ok_or_else(|| {
+                            invalid_resource(&name, format!("svg {id} has no resource {stored}"))
+                        })?
+                }
+            };
+            limits.check_resource(&name, data.len() as u64)?;
+            resources.insert(name, data);
+        }
+
+        Ok(RenderRequest {
+            package: SvgPackage {
+                svg: self.svg.into_bytes(),
+                resources,
+            },
+            vars: self.vars,
+            defaults: self.output,
+            variants: self.variants,
+            archive: self.archive,
+        })
+    }
+}
+
+fn invalid_resource(name: &str, message: String) -> ApiError {
+    ApiError::new(Status::UnprocessableEntity, "invalid_resource", message)
+        .with("resource", json!(name))
+}
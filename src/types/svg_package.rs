@@ -0,0 +1,97 @@
+use rocket::http::Status;
+use rocket::serde::json::json;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use time::OffsetDateTime;
+
+use super::{ApiError, SvgId};
+
+/// Name the svg itself is laid out under, which no resource may take
+pub const SVG_NAME: &str = "main.svg";
+
+/// An svg and the resources it refers to, held in memory
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SvgPackage {
+    pub svg: Vec<u8>,
+
+    /// Resource contents by the name the svg refers to them with
+    pub resources: HashMap<String, Vec<u8>>,
+}
+
+impl SvgPackage {
+    /// Total bytes of the svg and its resources
+    pub fn size(&self) -> u64 {
+        let resources: usize = self.resources.values().map(Vec::len).sum();
+        (self.svg.len() + resources) as u64
+    }
+}
+
+/// Normalize a resource name to a relative path like `img/logo.png`, or
+/// explain why it can't be one: it must not be absolute, contain `..` or NUL,
+/// or take the svg's own name.
+pub fn resource_name(name: &str) -> Result<String, ApiError> {
+    let invalid = |why: &str| {
+        ApiError::new(
+            Status::BadRequest,
+            "invalid_resource_name",
+            format!("resource {name:?} {why}"),
+        )
+        .with("resource", json!(name))
+    };
+    if name.contains('\0') {
+        return Err(invalid("contains NUL"));
+    }
+    if name.starts_with('/') || name.starts_with('\\') || name.get(1..2) == Some(":") {
+        return Err(invalid("must be a relative path"));
+    }
+    let mut parts = Vec::new();
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => continue,
+            ".." => return Err(invalid("must not contain ..")),
+            part => parts.push(part),
+        }
+    }
+    let normalized = parts.join("/");
+    if normalized.is_empty() {
+        return Err(invalid("must name a file"));
+    }
+    if normalized.eq_ignore_ascii_case(SVG_NAME) {
+        return Err(invalid("would replace the svg"));
+    }
+    Ok(normalized)
+}
+
+/// What a [`Store`](super::Store) knows about an svg it keeps
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SvgInfo {
+    pub id: SvgId,
+
+    /// Total bytes of the svg and its resources
+    pub size: u64,
+
+    /// Resource names, sorted
+    pub resources: Vec<String>,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated: OffsetDateTime,
+}
+
+impl SvgInfo {
+    /// Describe `package`, kept under `id` since `created`.
+    pub fn new(id: SvgId, package: &SvgPackage, created: OffsetDateTime) -> Self {
+        let mut resources: Vec<String> = package.resources.keys().cloned().collect();
+        resources.sort();
+        SvgInfo {
+            id,
+            size: package.size(),
+            resources,
+            created,
+            updated: OffsetDateTime::now_utc(),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use rocket::fs::TempFile;
+use std::collections::HashMap;
+use tokio::io::AsyncReadExt;
+
+use super::{svg_package::resource_name, Limits, Result, SvgPackage};
+
+/// An svg and its resources sent to be stored as a template
+#[derive(FromForm)]
+pub struct SvgUpload<'a> {
+    /// Raw svg content
+    pub svg: TempFile<'a>,
+
+    /// Files the svg refers to, by the name it uses for them
+    pub resources: HashMap<String, TempFile<'a>>,
+}
+
+impl SvgUpload<'_> {
+    /// Read every uploaded file into memory.
+    pub async fn package(&self, limits: &Limits) -> Result<SvgPackage> {
+        package(&self.svg, &self.resources, limits).await
+    }
+}
+
+/// Read an uploaded svg and its resources into memory, checking the resource names
+/// and that they are within `limits`
+pub(super) async fn package(
+    svg: &TempFile<'_>,
+    resources: &HashMap<String, TempFile<'_>>,
+    limits: &Limits,
+) -> Result<SvgPackage> {
+    limits.check_resource_count(resources.len())?;
+    let mut contents = HashMap::with_capacity(resources.len());
+    for (name, file) in resources {
+        let name = resource_name(name)?;
+        limits.check_resource(&name, file.len())?;
+        contents.insert(name, read(file).await?);
+    }
+    Ok(SvgPackage {
+        svg: read(svg).await?,
+        resources: contents,
+    })
+}
+
+async fn read(file: &TempFile<'_>) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(file.len() as usize);
+    file.open().await?.read_to_end(&mut data).await?;
+    Ok(data)
+}
@@ -0,0 +1,147 @@
+use super::{ApiError, Background, Encoding, FitMode, OutputFormat, Sizing};
+use crate::presets::Presets;
+use rocket::http::Status;
+use rocket::serde::json::json;
+use rocket::serde::Deserialize;
+
+// The rules for the output fields, checked by the forms' validators and by
+// `Variant::validate` for requests that didn't come from a form.
+
+pub const QUALITY_RANGE: &str = "quality must be 1-100";
+pub const WIDTH_POSITIVE: &str = "width must be positive";
+pub const HEIGHT_POSITIVE: &str = "height must be positive";
+pub const ZOOM_POSITIVE: &str = "zoom must be positive";
+
+pub fn valid_quality(quality: &Option<u8>) -> bool {
+    quality.is_none_or(|q| (1..=100).contains(&q))
+}
+
+pub fn valid_size(size: &Option<u32>) -> bool {
+    size.is_none_or(|s| s > 0)
+}
+
+pub fn valid_zoom(zoom: &Option<f32>) -> bool {
+    zoom.is_none_or(|z| z > 0.0 && z.is_finite())
+}
+
+/// One output to produce from an svg. Fields left unset fall back to the
+/// request's top level fields, and then to the preset.
+#[derive(FromForm, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Variant {
+    /// File name for this variant inside an archive, without extension
+    pub name: Option<String>,
+
+    pub preset: Option<String>,
+
+    pub format: Option<OutputFormat>,
+
+    #[field(validate = with(valid_quality, QUALITY_RANGE))]
+    pub quality: Option<u8>,
+
+    pub lossless: Option<bool>,
+
+    pub background: Option<Background>,
+
+    #[field(validate = with(valid_size, WIDTH_POSITIVE))]
+    pub width: Option<u32>,
+
+    #[field(validate = with(valid_size, HEIGHT_POSITIVE))]
+    pub height: Option<u32>,
+
+    #[field(validate = with(valid_zoom, ZOOM_POSITIVE))]
+    pub zoom: Option<f32>,
+
+    pub fit: Option<FitMode>,
+}
+
+/// A variant with its preset applied: exactly what to render
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub sizing: Sizing,
+    pub encoding: Encoding,
+}
+
+impl Variant {
+    /// Fill any unset fields from `defaults`.
+    pub fn or(self, defaults: &Variant) -> Variant {
+        Variant {
+            name: self.name.or_else(|| defaults.name.clone()),
+            preset: self.preset.or_else(|| defaults.preset.clone()),
+            format: self.format.or(defaults.format),
+            quality: self.quality.or(defaults.quality),
+            lossless: self.lossless.or(defaults.lossless),
+            background: self.background.or(defaults.background),
+            width: self.width.or(defaults.width),
+            height: self.height.or(defaults.height),
+            zoom: self.zoom.or(defaults.zoom),
+            fit: self.fit.or(defaults.fit),
+        }
+    }
+
+    /// Check what the form validators check, for variants that didn't come from a form.
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let invalid = |field: &'static str, message: &str| {
+            Err(
+                ApiError::new(Status::UnprocessableEntity, "invalid_field", message)
+                    .with("field", json!(field)),
+            )
+        };
+        if !valid_quality(&self.quality) {
+            return invalid("quality", QUALITY_RANGE);
+        }
+        if !valid_size(&self.width) {
+            return invalid("width", WIDTH_POSITIVE);
+        }
+        if !valid_size(&self.height) {
+            return invalid("height", HEIGHT_POSITIVE);
+        }
+        if !valid_zoom(&self.zoom) {
+            return invalid("zoom", ZOOM_POSITIVE);
+        }
+        Ok(())
+    }
+
+    /// Apply the named preset. A width or height on the variant replaces the
+    /// preset's size entirely, so one alone keeps the svg's aspect ratio.
+    /// `fallback` supplies the format when neither the variant nor its preset picks one.
+    pub fn resolve(
+        &self,
+        presets: &Presets,
+        fallback: impl FnOnce() -> Result<OutputFormat, ApiError>,
+    ) -> Result<Target, ApiError> {
+        let preset = match &self.preset {
+            None => None,
+            Some(name) => Some(presets.get(name).ok_or_else(|| {
+                ApiError::new(
+                    Status::UnprocessableEntity,
+                    "unknown_preset",
+                    format!("no preset named {name:?}"),
+                )
+            })?),
+        };
+        let format = match self.format.or(preset.and_then(|p| p.format)) {
+            Some(format) => format,
+            None => fallback()?,
+        };
+        let (width, height) = match (self.width, self.height) {
+            (None, None) => (preset.map(|p| p.width), preset.map(|p| p.height)),
+            size => size,
+        };
+        Ok(Target {
+            sizing: Sizing {
+                width,
+                height,
+                zoom: self.zoom,
+                fit: self.fit.or(preset.map(|p| p.fit)).unwrap_or_default(),
+            },
+            encoding: Encoding {
+                format,
+                quality: self.quality.unwrap_or(Encoding::DEFAULT_QUALITY),
+                lossless: self.lossless.unwrap_or(false),
+                background: self.background,
+                max_bytes: preset.and_then(|p| p.max_bytes),
+            },
+        })
+    }
+}